@@ -1,18 +1,23 @@
 use crate::error::err_exit;
+use anyhow::anyhow;
 use anyhow::Result;
 use clap::App;
 use clap::Arg;
 use clap::ArgGroup;
 use once_cell::sync::Lazy;
 use serde::Deserialize;
+use shadow_peer::client::TimeoutSecs;
 use std::fs::File;
 use std::io::BufReader;
 use std::io::Read;
+use std::path::Path;
 use std::path::PathBuf;
 
 pub struct Config {
     pub daemon: bool,
     pub log: Option<PathBuf>,
+    /// Read again on reload, the sample is not reloaded
+    pub path: Option<PathBuf>,
     pub conf: Conf,
 }
 
@@ -25,8 +30,37 @@ pub struct Conf {
 #[derive(Deserialize)]
 pub struct Server {
     pub proto: String,
-    pub addr: String,
+    pub addr: Addr,
+    /// "priority" or "round-robin"
+    pub failover: Option<String>,
+    /// Servers to stay connected to at once
+    pub simultaneous: Option<usize>,
     pub client: String,
+    pub secret: String,
+    pub ca: Option<PathBuf>,
+    pub domain: Option<String>,
+    #[serde(default)]
+    pub multiplex: bool,
+    #[serde(default)]
+    pub pool: usize,
+    /// Seconds given to in-flight streams once shut down
+    pub drain: Option<u64>,
+    /// Address of the Prometheus exporter
+    pub metrics: Option<String>,
+    /// Failed connections in a row before exiting, unlimited if not given
+    pub max_attempts: Option<u32>,
+    /// Pings, read, connect and reconnect timeouts, keepalive and idle
+    /// timeouts
+    #[serde(flatten)]
+    pub timeouts: TimeoutSecs,
+}
+
+/// One server, or several to fail over between
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum Addr {
+    One(String),
+    Many(Vec<String>),
 }
 
 #[derive(Deserialize)]
@@ -35,6 +69,12 @@ pub struct PortMap {
     pub port: String,
     pub dproto: String,
     pub addr: String,
+    /// Ask the server to listen on `port` for this client
+    #[serde(default)]
+    pub register: bool,
+    pub bind: Option<String>,
+    /// "v1" or "v2", tell a TCP service the visitor's address
+    pub proxy_protocol: Option<String>,
 }
 
 pub static CONFIG: Lazy<Config> = Lazy::new(parse_config);
@@ -93,7 +133,7 @@ fn command_config() -> App<'static, 'static> {
 fn parse_config() -> Config {
     match parse_config_impl() {
         Ok(config) => config,
-        Err(e) => err_exit(1, &format!("init error {}", e)),
+        Err(e) => err_exit(1, format!("init error {}", e)),
     }
 }
 
@@ -103,26 +143,86 @@ fn parse_config_impl() -> Result<Config> {
     if matches.is_present("dump config") {
         dump_config();
     }
-    let conf = if let Some(conf) = matches.value_of("config") {
-        let conf = File::open(conf)?;
-        let mut conf = BufReader::new(conf);
-        let mut content = String::new();
-        conf.read_to_string(&mut content)?;
-        toml::from_str(&content)?
-    } else {
-        toml::from_str(SAMPLE)?
+    let path = matches.value_of("config").map(PathBuf::from);
+    let conf = match &path {
+        Some(path) => read_conf(path)?,
+        None => toml::from_str(SAMPLE)?,
     };
 
     let daemon = matches.is_present("daemon");
     let log = matches.value_of("log").map(PathBuf::from);
 
-    Ok(Config { daemon, log, conf })
+    Ok(Config {
+        daemon,
+        log,
+        path,
+        conf,
+    })
+}
+
+/// Read the config file again
+pub fn reload() -> Result<Conf> {
+    let path = CONFIG.path.as_ref();
+    let path = path.ok_or_else(|| anyhow!("No config file to reload"))?;
+    read_conf(path)
+}
+
+fn read_conf(path: &Path) -> Result<Conf> {
+    let conf = File::open(path)?;
+    let mut conf = BufReader::new(conf);
+    let mut content = String::new();
+    conf.read_to_string(&mut content)?;
+    Ok(toml::from_str(&content)?)
 }
 
-const SAMPLE: &str = r#"[server]
+const SAMPLE: &str = r#"# [[portmap]] is reloaded on SIGHUP, [server] is not
+
+[server]
 proto = "tcp"
+# host:port, resolved on each connection, IPv4 and IPv6 addresses tried
+# in turn
 addr = "[::1]:32767"
+# addr = "relay.example.com:32767"
+# Or several servers, tried in order, or in turn if failover is
+# "round-robin"
+# addr = ["[::1]:32767", "[::1]:32768"]
+# failover = "priority"
+# Stay connected to so many of them at once, each one a separate server
+# simultaneous = 1
 client = "BITCOINCASH:QPZNZ089TQKAVWF6XM6SD8KPGM59FF5H6CKV0585EP"
+secret = "CHANGE ME"
+# For proto = "tls", pin the server certificate to this CA
+# ca = "/etc/shadow-peer/ca.crt"
+# domain = "shadow-peer.example.com"
+# Carry all visitors over the control connection
+multiplex = false
+# Idle worker connections parked at the server, unused if multiplexed
+pool = 0
+# Seconds given to in-flight streams on SIGTERM / SIGINT
+drain = 30
+# Prometheus metrics at http://127.0.0.1:9101/metrics
+# metrics = "127.0.0.1:9101"
+# Seconds between pings to the server, reconnect after missed_pings
+# unanswered
+ping = 5
+missed_pings = 3
+# Longest wait for the next message of the server, must be above ping
+read_timeout = 10
+# Longest wait to connect to the server
+connect_timeout = 10
+# Before connecting again once the server is lost, doubled after each
+# failure up to reconnect_max, less up to half of it at random. Must be
+# above zero and at most reconnect_max
+reconnect = 3
+reconnect_max = 60
+# Exit after so many failed connections in a row, retry forever if not
+# given
+# max_attempts = 10
+# TCP keepalive of local service connections, off if not given
+# keepalive = 60
+# Close TCP sessions idle both ways, never if not given
+# idle_timeout = 3600
+udp_idle_timeout = 60
 
 [[portmap]]
 sproto = "tcp"
@@ -136,6 +236,38 @@ port = "8443"
 dproto = "tcp"
 addr = "[::1]:443"
 
+[[portmap]]
+sproto = "udp"
+port = "5353"
+dproto = "udp"
+addr = "[::1]:53"
+
+# Tell the service the visitor's address with a PROXY header first, "v1"
+# or "v2", TCP only
+# [[portmap]]
+# sproto = "tcp"
+# port = "8080"
+# dproto = "tcp"
+# addr = "[::1]:8080"
+# proxy_protocol = "v2"
+
+# Local services may be given by name as well
+# [[portmap]]
+# sproto = "tcp"
+# port = "8022"
+# dproto = "tcp"
+# addr = "nas.lan:22"
+
+# Have the server listen on port 8080 while this client is connected,
+# within the server's `allow` list for this client
+# [[portmap]]
+# sproto = "tcp"
+# port = "8080"
+# dproto = "tcp"
+# addr = "[::1]:8080"
+# register = true
+# bind = "0.0.0.0"
+
 # Save this as an .toml file."#;
 
 fn dump_config() -> ! {
@@ -1,3 +1,5 @@
+use self::config::Addr;
+use self::config::Conf;
 use self::config::CONFIG;
 use self::error::err_exit;
 use anyhow::anyhow;
@@ -6,19 +8,62 @@ use async_std::task;
 use daemonize::Daemonize;
 use shadow_peer::client::Client;
 use shadow_peer::client::ClientId;
-use std::net::SocketAddr;
+use shadow_peer::client::Dest;
+use shadow_peer::client::Failover;
+use shadow_peer::client::Proto;
+use shadow_peer::client::ProxyProtocol;
+use shadow_peer::client::Remote;
+use shadow_peer::client::Settings;
+use shadow_peer::client::Transport;
+use shadow_peer::tls;
+use std::time::Duration;
 
 mod config;
 mod error;
 mod log;
+mod signal;
 
 fn main() -> Result<()> {
-    let server = parse_server()?;
+    let (servers, transport) = parse_server()?;
     let client_id = ClientId::from(&CONFIG.conf.server.client);
-    let port_map = CONFIG.conf.portmap.iter().map(port_map_mapper).collect();
+    let secret = CONFIG.conf.server.secret.clone();
+    let conf = settings(&CONFIG.conf).unwrap_or_else(|e| err_exit(1, e));
     log::init_logger();
     daemonize();
-    task::block_on(Client::new(server, client_id, port_map).run());
+    let mut client = Client::new(
+        servers[0].clone(),
+        transport.clone(),
+        client_id,
+        secret,
+        conf.port_map,
+    )
+    .multiplex(CONFIG.conf.server.multiplex)
+    .pool(CONFIG.conf.server.pool)
+    .register(conf.remote)
+    .failover(parse_failover()?)
+    .simultaneous(CONFIG.conf.server.simultaneous.unwrap_or(1));
+    for server in &servers[1..] {
+        client = client.fallback(server.clone(), transport.clone());
+    }
+    if let Some(drain) = CONFIG.conf.server.drain {
+        client = client.drain(Duration::from_secs(drain));
+    }
+    if let Some(metrics) = &CONFIG.conf.server.metrics {
+        client = client.metrics(metrics.parse()?);
+    }
+    client = client.timeouts(CONFIG.conf.server.timeouts.timeouts()?);
+    if let Some(attempts) = CONFIG.conf.server.max_attempts {
+        client = client.max_attempts(attempts);
+    }
+    let reload = client.reload_handle();
+    let reload = move || {
+        reload.reload(settings(&config::reload()?)?);
+        Ok(())
+    };
+    signal::watch(client.shutdown_handle(), reload)?;
+    if let Err(e) = task::block_on(client.run()) {
+        err_exit(1, e);
+    }
     Ok(())
 }
 
@@ -27,38 +72,98 @@ fn daemonize() {
         return;
     }
     Daemonize::new()
-        .pid_file(format!("/tmp/shadow-peer-client.pid"))
+        .pid_file("/tmp/shadow-peer-client.pid")
         .working_directory("/tmp")
         .umask(0o777)
         .start()
         .expect("Failed to start as daemon");
 }
 
-fn parse_server() -> Result<SocketAddr> {
+fn parse_server() -> Result<(Vec<String>, Transport)> {
     let conf = &CONFIG.conf.server;
-    match conf.proto.as_ref() {
-        "tcp" => Ok(conf.addr.parse()?),
-        proto => Err(anyhow!("Unsupported protocol {}", proto)),
+    let transport = match conf.proto.as_ref() {
+        "tcp" => Transport::Tcp,
+        "tls" => {
+            let ca = conf.ca.as_ref().ok_or_else(|| anyhow!("TLS ca required"))?;
+            let domain = conf.domain.clone();
+            let domain = domain.ok_or_else(|| anyhow!("TLS domain required"))?;
+            Transport::Tls(tls::connector(ca)?, domain)
+        }
+        proto => Err(anyhow!("Unsupported protocol {}", proto))?,
+    };
+    let addr = match &conf.addr {
+        Addr::One(addr) => vec![host_port(addr)?],
+        Addr::Many(addr) => addr.iter().map(|a| host_port(a)).collect::<Result<_>>()?,
+    };
+    if addr.is_empty() {
+        Err(anyhow!("No server addr"))?;
     }
+    Ok((addr, transport))
 }
 
-fn port_map_mapper(pm: &config::PortMap) -> (u16, SocketAddr) {
-    match port_map_mapper_impl(pm) {
-        Ok(r) => r,
-        Err(e) => err_exit(1, e),
+fn parse_failover() -> Result<Failover> {
+    match CONFIG.conf.server.failover.as_deref() {
+        None | Some("priority") => Ok(Failover::Priority),
+        Some("round-robin") => Ok(Failover::RoundRobin),
+        Some(failover) => Err(anyhow!("Unsupported failover {}", failover)),
     }
 }
 
-fn port_map_mapper_impl(pm: &config::PortMap) -> Result<(u16, SocketAddr)> {
-    let port = match pm.sproto.as_ref() {
-        "tcp" => pm.port.parse()?,
-        proto => Err(anyhow!("Unsupported protocol {}", proto))?,
+/// The port maps of `conf`, and the listeners to register
+fn settings(conf: &Conf) -> Result<Settings> {
+    let port_map = conf.portmap.iter().map(port_map_mapper);
+    let port_map = port_map.collect::<Result<_>>()?;
+    let remote = conf.portmap.iter().filter(|pm| pm.register);
+    let remote = remote.map(remote_mapper).collect::<Result<_>>()?;
+    Ok(Settings { port_map, remote })
+}
+
+fn port_map_mapper(pm: &config::PortMap) -> Result<(Proto, u16, Dest)> {
+    let sproto = parse_proto(&pm.sproto)?;
+    let dproto = parse_proto(&pm.dproto)?;
+    if sproto != dproto {
+        Err(anyhow!("Protocol mismatch {} -> {}", pm.sproto, pm.dproto))?;
+    }
+    let proxy = match pm.proxy_protocol.as_deref() {
+        None => None,
+        Some(_) if sproto == Proto::Udp => Err(anyhow!("PROXY protocol over UDP"))?,
+        Some("v1") => Some(ProxyProtocol::V1),
+        Some("v2") => Some(ProxyProtocol::V2),
+        Some(version) => Err(anyhow!("Unsupported PROXY protocol {}", version))?,
+    };
+    let dest = Dest {
+        addr: host_port(&pm.addr)?,
+        proxy,
     };
+    Ok((sproto, pm.port.parse()?, dest))
+}
 
-    let addr = match pm.dproto.as_ref() {
-        "tcp" => pm.addr.parse()?,
-        proto => Err(anyhow!("Unsupported protocol {}", proto))?,
+fn remote_mapper(pm: &config::PortMap) -> Result<Remote> {
+    let bind = match &pm.bind {
+        Some(bind) => Some(bind.parse()?),
+        None => None,
     };
+    Ok(Remote {
+        proto: parse_proto(&pm.sproto)?,
+        port: pm.port.parse()?,
+        bind,
+    })
+}
 
-    Ok((port, addr))
+/// `addr` if it is `host:port`, left to resolve on each connection
+fn host_port(addr: &str) -> Result<String> {
+    match addr.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {
+            Ok(addr.to_string())
+        }
+        _ => Err(anyhow!("Bad address {}, expect host:port", addr)),
+    }
+}
+
+fn parse_proto(proto: &str) -> Result<Proto> {
+    match proto {
+        "tcp" => Ok(Proto::Tcp),
+        "udp" => Ok(Proto::Udp),
+        proto => Err(anyhow!("Unsupported protocol {}", proto)),
+    }
 }
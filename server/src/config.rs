@@ -1,31 +1,79 @@
 use crate::error::err_exit;
+use anyhow::anyhow;
 use anyhow::Result;
 use clap::App;
 use clap::Arg;
 use clap::ArgGroup;
 use once_cell::sync::Lazy;
 use serde::Deserialize;
+use shadow_peer::server::TimeoutSecs;
 use std::fs::File;
 use std::io::BufReader;
 use std::io::Read;
+use std::path::Path;
 use std::path::PathBuf;
 
 pub struct Config {
     pub daemon: bool,
     pub log: Option<PathBuf>,
+    /// Read again on reload, the sample is not reloaded
+    pub path: Option<PathBuf>,
     pub conf: Conf,
 }
 
 #[derive(Deserialize)]
 pub struct Conf {
+    /// Seconds given to visitors once shut down
+    pub drain: Option<u64>,
+    /// Address of the admin API, and the bearer token it requires
+    pub admin: Option<String>,
+    pub admin_token: Option<String>,
+    /// Address of the Prometheus exporter
+    pub metrics: Option<String>,
+    /// "round-robin" or "least-connections"
+    pub balance: Option<String>,
+    /// Pings, read and connect timeouts, keepalive and idle timeouts
+    #[serde(flatten)]
+    pub timeouts: TimeoutSecs,
+    /// Bytes per second each way, of all visitors, and the burst
+    pub rate_limit: Option<u64>,
+    pub rate_burst: Option<u64>,
+    /// New visitor sessions per second from one source IP, and the burst
+    pub conn_rate: Option<u32>,
+    pub conn_burst: Option<u32>,
+    /// Visitors of one client waiting for its worker connection
+    pub max_pending: Option<usize>,
+    /// Visitors let in by address, to all listeners
+    #[serde(flatten)]
+    pub access: Access,
+    pub auth: Vec<Auth>,
     pub client: Vec<Client>,
+    #[serde(default)]
     pub listen: Vec<Listen>,
+    #[serde(default)]
+    pub vhost: Vec<VHost>,
+}
+
+#[derive(Deserialize)]
+pub struct Auth {
+    pub client: String,
+    pub secret: String,
+    /// Ports the client may register, as "port" or "first-last"
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// Unlimited if not given
+    pub max_listen: Option<usize>,
+    /// Of the visitors of all listeners of the client
+    pub rate_limit: Option<u64>,
+    pub rate_burst: Option<u64>,
 }
 
 #[derive(Deserialize)]
 pub struct Client {
     pub proto: String,
     pub listen: String,
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
 }
 
 #[derive(Deserialize)]
@@ -33,6 +81,46 @@ pub struct Listen {
     pub proto: String,
     pub listen: String,
     pub client: String,
+    /// Of the visitors of this listener
+    pub rate_limit: Option<u64>,
+    pub rate_burst: Option<u64>,
+    /// Sessions at once, unlimited if not given
+    pub max_sessions: Option<usize>,
+    /// Connections come from a load balancer, with a PROXY header first
+    #[serde(default)]
+    pub proxy_protocol: bool,
+    #[serde(flatten)]
+    pub access: Access,
+}
+
+/// A host served on a listener shared by clients
+#[derive(Deserialize)]
+pub struct VHost {
+    /// "http" or "tls"
+    pub proto: String,
+    pub listen: String,
+    pub host: String,
+    pub client: String,
+    /// Of the client's [[portmap]], the listen port if not given
+    pub port: Option<u16>,
+    /// Of the visitors of the whole listener, given on one of its hosts
+    pub rate_limit: Option<u64>,
+    pub rate_burst: Option<u64>,
+    pub max_sessions: Option<usize>,
+    #[serde(flatten)]
+    pub access: Access,
+}
+
+/// CIDR lists, given inline or as files of one per line, read again on
+/// reload
+#[derive(Deserialize)]
+pub struct Access {
+    #[serde(default)]
+    pub allow_from: Vec<String>,
+    #[serde(default)]
+    pub deny_from: Vec<String>,
+    pub allow_from_file: Option<PathBuf>,
+    pub deny_from_file: Option<PathBuf>,
 }
 
 pub static CONFIG: Lazy<Config> = Lazy::new(parse_config);
@@ -91,7 +179,7 @@ fn command_config() -> App<'static, 'static> {
 fn parse_config() -> Config {
     match parse_config_impl() {
         Ok(config) => config,
-        Err(e) => err_exit(1, &format!("init error {}", e)),
+        Err(e) => err_exit(1, format!("init error {}", e)),
     }
 }
 
@@ -101,26 +189,110 @@ fn parse_config_impl() -> Result<Config> {
     if matches.is_present("dump config") {
         dump_config();
     }
-    let conf = if let Some(conf) = matches.value_of("config") {
-        let conf = File::open(conf)?;
-        let mut conf = BufReader::new(conf);
-        let mut content = String::new();
-        conf.read_to_string(&mut content)?;
-        toml::from_str(&content)?
-    } else {
-        toml::from_str(SAMPLE)?
+    let path = matches.value_of("config").map(PathBuf::from);
+    let conf = match &path {
+        Some(path) => read_conf(path)?,
+        None => toml::from_str(SAMPLE)?,
     };
 
     let daemon = matches.is_present("daemon");
     let log = matches.value_of("log").map(PathBuf::from);
 
-    Ok(Config { daemon, log, conf })
+    Ok(Config {
+        daemon,
+        log,
+        path,
+        conf,
+    })
+}
+
+/// Read the config file again
+pub fn reload() -> Result<Conf> {
+    let path = CONFIG.path.as_ref();
+    let path = path.ok_or_else(|| anyhow!("No config file to reload"))?;
+    read_conf(path)
+}
+
+fn read_conf(path: &Path) -> Result<Conf> {
+    let conf = File::open(path)?;
+    let mut conf = BufReader::new(conf);
+    let mut content = String::new();
+    conf.read_to_string(&mut content)?;
+    Ok(toml::from_str(&content)?)
 }
 
-const SAMPLE: &str = r#"[[client]]
+const SAMPLE: &str = r#"# Reloaded on SIGHUP, except [[client]], drain, admin, metrics,
+# balance and timeouts
+
+# Seconds given to visitors on SIGTERM / SIGINT
+drain = 30
+# JSON API to inspect and control the server. With admin_token, requests
+# must carry "Authorization: Bearer <admin_token>", it is required off
+# loopback.
+# admin = "127.0.0.1:32766"
+# admin_token = "CHANGE ME"
+# Prometheus metrics at http://127.0.0.1:9100/metrics
+# metrics = "127.0.0.1:9100"
+# A client may log in more than once with the same id, its visitors are
+# spread among its connections "round-robin" or to the one serving the
+# fewest sessions, "least-connections". One which drops leaves the others
+# serving.
+balance = "round-robin"
+
+# Seconds between pings to each client, dropped after missed_pings
+# unanswered
+ping = 5
+missed_pings = 3
+# Longest wait for the next message of a client, must be above ping
+read_timeout = 10
+# Longest wait for a client to connect back with the stream of a visitor
+connect_timeout = 10
+# TCP keepalive of visitor connections, off if not given
+# keepalive = 60
+# Close TCP visitor sessions idle both ways, never if not given
+# idle_timeout = 3600
+udp_idle_timeout = 60
+# Bytes per second of all visitors, each way, unlimited if not given.
+# Bursts up to rate_burst bytes, no less than 65535 which holds the largest
+# datagram, rate_limit or 65535 if not given. Limits may be set per client
+# and per listener as well, the usage is logged each minute. Only the
+# server limits, clients relay whatever it lets through.
+# rate_limit = 10485760
+# rate_burst = 10485760
+# New visitor sessions per second from one source IP, bursts up to
+# conn_burst, conn_rate if not given. Unlimited if not given, as well as
+# max_sessions per [[listen]], but for UDP listeners which hold 4096
+# sessions at most. Sources beyond 4096 still throttled are refused.
+# conn_rate = 10
+# conn_burst = 20
+# Visitors of one client waiting for it to connect back, those over it
+# are dropped at once
+# max_pending = 256
+# Visitors let in by address, to all listeners and per [[listen]]. Those
+# in deny_from are not, if allow_from is given only those in it are. The
+# files hold one CIDR per line, # comments, and are read again on reload.
+# allow_from = ["10.0.0.0/8", "2001:db8::/32"]
+# deny_from = ["10.0.13.0/24"]
+# deny_from_file = "/etc/shadow-peer/deny.txt"
+
+[[auth]]
+client = "BITCOINCASH:QPZNZ089TQKAVWF6XM6SD8KPGM59FF5H6CKV0585EP"
+secret = "CHANGE ME"
+# Ports the client may ask to listen on by itself
+# allow = ["8080", "9000-9099"]
+# max_listen = 4
+# rate_limit = 1048576
+
+[[client]]
 proto = "tcp"
 listen = "[::]:32767"
 
+# [[client]]
+# proto = "tls"
+# listen = "[::]:32768"
+# cert = "/etc/shadow-peer/server.crt"
+# key = "/etc/shadow-peer/server.key"
+
 [[listen]]
 proto = "tcp"
 listen = "[::]:8000"
@@ -130,6 +302,42 @@ client = "BITCOINCASH:QPZNZ089TQKAVWF6XM6SD8KPGM59FF5H6CKV0585EP"
 proto = "tcp"
 listen = "[::]:8443"
 client = "BITCOINCASH:QPZNZ089TQKAVWF6XM6SD8KPGM59FF5H6CKV0585EP"
+# rate_limit = 524288
+# max_sessions = 100
+# allow_from = ["192.168.0.0/16"]
+# Behind a load balancer which sends a PROXY v1 or v2 header first, the
+# visitor address is taken from it. Keep the listener out of reach of
+# anyone else.
+# proxy_protocol = true
+
+[[listen]]
+proto = "udp"
+listen = "[::]:5353"
+client = "BITCOINCASH:QPZNZ089TQKAVWF6XM6SD8KPGM59FF5H6CKV0585EP"
+
+# Share one port among several clients, visitors are routed by the Host
+# header of their first HTTP request. `host` may be "*.example.com" for
+# any subdomain, or "*" for any host. The client maps the visitors by
+# `port`, the listen port if not given.
+# [[vhost]]
+# proto = "http"
+# listen = "[::]:80"
+# host = "app.example.com"
+# client = "BITCOINCASH:QPZNZ089TQKAVWF6XM6SD8KPGM59FF5H6CKV0585EP"
+# port = 8000
+# Access and limits of the whole listener, as of a [[listen]], on one of
+# its hosts only
+# rate_limit = 10485760
+# max_sessions = 1000
+# deny_from = ["10.0.13.0/24"]
+
+# With proto "tls" visitors are routed by the server name (SNI) of their
+# TLS handshake, which is left to the client's service, end to end.
+# [[vhost]]
+# proto = "tls"
+# listen = "[::]:443"
+# host = "app.example.com"
+# client = "BITCOINCASH:QPZNZ089TQKAVWF6XM6SD8KPGM59FF5H6CKV0585EP"
 
 # Save this as an .toml file."#;
 
@@ -11,7 +11,7 @@ pub fn init_logger() {
     let term = TermLogger::new(LevelFilter::Debug, config(), TerminalMode::Mixed);
     let mut logger: Vec<Box<dyn SharedLogger>> = vec![term];
     if let Some(ref path) = CONFIG.log {
-        let file = File::create(&path).expect(&format!("Unable to create {:?}", &path));
+        let file = File::create(path).unwrap_or_else(|_| panic!("Unable to create {:?}", path));
         let writer = WriteLogger::new(LevelFilter::Info, config(), file);
         logger.push(writer);
     }
@@ -1,24 +1,74 @@
+use self::config::Conf;
 use self::config::CONFIG;
 use self::error::err_exit;
 use anyhow::anyhow;
 use anyhow::Result;
 use async_std::task;
 use daemonize::Daemonize;
+use shadow_peer::server::Access;
+use shadow_peer::server::AccessLists;
+use shadow_peer::server::Balance;
+use shadow_peer::server::Cidr;
 use shadow_peer::server::CliListen;
 use shadow_peer::server::ClientId;
+use shadow_peer::server::ConnLimits;
 use shadow_peer::server::Listen;
+use shadow_peer::server::Policy;
+use shadow_peer::server::Rate;
+use shadow_peer::server::RateLimits;
 use shadow_peer::server::Server;
+use shadow_peer::server::Settings;
+use shadow_peer::server::VHost;
+use shadow_peer::server::VHostListen;
+use shadow_peer::tls;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
 
 mod config;
 mod error;
 mod log;
+mod signal;
 
 fn main() {
-    let listen = CONFIG.conf.listen.iter().map(listen_mapper).collect();
+    let conf = settings(&CONFIG.conf).unwrap_or_else(|e| err_exit(1, e));
     let cli = CONFIG.conf.client.iter().map(cli_mapper).collect();
     log::init_logger();
     daemonize();
-    task::block_on(Server::new(listen, cli).run())
+    let mut server = Server::new(conf.listen, cli, conf.secret)
+        .policy(conf.policy)
+        .rate_limits(conf.rate_limits)
+        .conn_limits(conf.conn_limits)
+        .access(conf.access)
+        .proxied(conf.proxied)
+        .vhosts(conf.vhosts);
+    if let Some(drain) = CONFIG.conf.drain {
+        server = server.drain(Duration::from_secs(drain));
+    }
+    if let Some(admin) = &CONFIG.conf.admin {
+        server = server.admin(admin.parse().unwrap_or_else(|e| err_exit(1, e)));
+    }
+    if let Some(token) = &CONFIG.conf.admin_token {
+        server = server.admin_token(token.clone());
+    }
+    if let Some(metrics) = &CONFIG.conf.metrics {
+        server = server.metrics(metrics.parse().unwrap_or_else(|e| err_exit(1, e)));
+    }
+    if let Some(balance) = &CONFIG.conf.balance {
+        server = server.balance(balance_mapper(balance).unwrap_or_else(|e| err_exit(1, e)));
+    }
+    let timeouts = CONFIG.conf.timeouts.timeouts();
+    server = server.timeouts(timeouts.unwrap_or_else(|e| err_exit(1, e)));
+    let reload = server.reload_handle();
+    let reload = move || {
+        reload.reload(settings(&config::reload()?)?);
+        Ok(())
+    };
+    signal::watch(server.shutdown_handle(), reload).unwrap_or_else(|e| err_exit(1, e));
+    if let Err(errors) = task::block_on(server.run()) {
+        err_exit(1, anyhow!("{} listeners failed, none left", errors.len()));
+    }
 }
 
 fn daemonize() {
@@ -26,7 +76,7 @@ fn daemonize() {
         return;
     }
     Daemonize::new()
-        .pid_file(format!("/tmp/shadow-peer-server.pid"))
+        .pid_file("/tmp/shadow-peer-server.pid")
         .working_directory("/tmp")
         .umask(0o777)
         .start()
@@ -43,20 +93,213 @@ fn cli_mapper(c: &config::Client) -> CliListen {
 fn cli_mapper_impl(c: &config::Client) -> Result<CliListen> {
     match c.proto.as_ref() {
         "tcp" => Ok(CliListen::Tcp(c.listen.parse()?)),
+        "tls" => {
+            let cert = c
+                .cert
+                .as_ref()
+                .ok_or_else(|| anyhow!("TLS cert required"))?;
+            let key = c.key.as_ref().ok_or_else(|| anyhow!("TLS key required"))?;
+            Ok(CliListen::Tls(c.listen.parse()?, tls::acceptor(cert, key)?))
+        }
         proto => Err(anyhow!("Unsupported protocol {}", proto)),
     }
 }
 
-fn listen_mapper(l: &config::Listen) -> (Listen, ClientId) {
-    match listen_mapper_impl(l) {
-        Ok(r) => r,
-        Err(e) => err_exit(1, e),
+/// Everything in `conf` but the client listeners
+fn settings(conf: &Conf) -> Result<Settings> {
+    let listen = conf.listen.iter().map(listen_mapper);
+    let listen = listen.collect::<Result<Vec<_>>>()?;
+    let vhosts = conf.vhost.iter().map(vhost_mapper);
+    let vhosts = vhosts.collect::<Result<Vec<_>>>()?;
+    let secret: Vec<_> = conf.auth.iter().map(auth_mapper).collect();
+    let clients = listen.iter().map(|(_, id)| id);
+    for id in clients.chain(vhosts.iter().map(|(_, v)| &v.client)) {
+        if !secret.iter().any(|(sid, _)| sid == id) {
+            Err(anyhow!("No [[auth]] secret for client {}", id))?;
+        }
+    }
+    let policy = conf.auth.iter().filter(|a| !a.allow.is_empty());
+    let policy = policy.map(policy_mapper).collect::<Result<_>>()?;
+    let rate_limits = rate_limits(conf)?;
+    let conn_limits = conn_limits(conf)?;
+    let access = access_lists(conf)?;
+    let proxied = conf.listen.iter().filter(|l| l.proxy_protocol);
+    let proxied = proxied.map(|l| match listen_mapper(l)?.0 {
+        Listen::Udp(_) => Err(anyhow!("PROXY protocol over UDP")),
+        listen => Ok(listen),
+    });
+    let proxied = proxied.collect::<Result<_>>()?;
+    Ok(Settings {
+        listen,
+        secret,
+        policy,
+        rate_limits,
+        conn_limits,
+        access,
+        proxied,
+        vhosts,
+    })
+}
+
+fn access_lists(conf: &Conf) -> Result<AccessLists> {
+    let mut listen = vec![];
+    for l in &conf.listen {
+        listen.push((listen_mapper(l)?.0, access_mapper(&l.access)?));
     }
+    let vhost = per_vhost(conf, |v| {
+        let access = access_mapper(&v.access)?;
+        Ok(Some(access).filter(|a| *a != Access::default()))
+    })?;
+    Ok(AccessLists {
+        global: access_mapper(&conf.access)?,
+        listen,
+        vhost,
+    })
 }
 
-fn listen_mapper_impl(l: &config::Listen) -> Result<(Listen, ClientId)> {
+fn access_mapper(a: &config::Access) -> Result<Access> {
+    let mut allow = cidrs(a.allow_from.iter().map(String::as_str))?;
+    let mut deny = cidrs(a.deny_from.iter().map(String::as_str))?;
+    if let Some(path) = &a.allow_from_file {
+        allow.extend(cidr_file(path)?);
+    }
+    if let Some(path) = &a.deny_from_file {
+        deny.extend(cidr_file(path)?);
+    }
+    Ok(Access { allow, deny })
+}
+
+/// One CIDR per line, blank lines and # comments are skipped
+fn cidr_file(path: &Path) -> Result<Vec<Cidr>> {
+    let content = fs::read_to_string(path).map_err(|e| anyhow!("{:?}: {}", path, e))?;
+    let lines = content
+        .lines()
+        .map(|l| l.split('#').next().unwrap_or("").trim());
+    cidrs(lines.filter(|l| !l.is_empty()))
+}
+
+fn cidrs<'a>(cidrs: impl Iterator<Item = &'a str>) -> Result<Vec<Cidr>> {
+    Ok(cidrs.map(str::parse).collect::<Result<_, _>>()?)
+}
+
+fn conn_limits(conf: &Conf) -> Result<ConnLimits> {
+    let mut max_sessions = vec![];
+    for l in &conf.listen {
+        if let Some(max) = l.max_sessions {
+            max_sessions.push((listen_mapper(l)?.0, max));
+        }
+    }
+    let per_ip = conf
+        .conn_rate
+        .map(|rate| (rate, conf.conn_burst.unwrap_or(rate)));
+    Ok(ConnLimits {
+        max_sessions,
+        vhost_sessions: per_vhost(conf, |v| Ok(v.max_sessions))?,
+        per_ip,
+        max_pending: conf.max_pending,
+    })
+}
+
+/// The global, per listener and per client bandwidth limits of `conf`
+fn rate_limits(conf: &Conf) -> Result<RateLimits> {
+    let mut listen = vec![];
+    for l in &conf.listen {
+        if let Some(rate) = rate(l.rate_limit, l.rate_burst)? {
+            listen.push((listen_mapper(l)?.0, rate));
+        }
+    }
+    let mut client = vec![];
+    for a in &conf.auth {
+        if let Some(rate) = rate(a.rate_limit, a.rate_burst)? {
+            client.push((ClientId::from(&a.client), rate));
+        }
+    }
+    Ok(RateLimits {
+        global: rate(conf.rate_limit, conf.rate_burst)?,
+        listen,
+        vhost: per_vhost(conf, |v| rate(v.rate_limit, v.rate_burst))?,
+        client,
+    })
+}
+
+fn rate(limit: Option<u64>, burst: Option<u64>) -> Result<Option<Rate>> {
+    let mut rate = match limit {
+        Some(limit) => Rate::new(limit),
+        None => return Ok(None),
+    };
+    rate.burst = burst.unwrap_or(rate.burst);
+    rate.validate()?;
+    Ok(Some(rate))
+}
+
+fn balance_mapper(balance: &str) -> Result<Balance> {
+    match balance {
+        "round-robin" => Ok(Balance::RoundRobin),
+        "least-connections" => Ok(Balance::LeastConnections),
+        balance => Err(anyhow!("Unsupported balance {}", balance)),
+    }
+}
+
+fn listen_mapper(l: &config::Listen) -> Result<(Listen, ClientId)> {
     match l.proto.as_ref() {
         "tcp" => Ok((Listen::Tcp(l.listen.parse()?), ClientId::from(&l.client))),
+        "udp" => Ok((Listen::Udp(l.listen.parse()?), ClientId::from(&l.client))),
         proto => Err(anyhow!("Unsupported protocol {}", proto)),
     }
 }
+
+fn vhost_mapper(v: &config::VHost) -> Result<(VHostListen, VHost)> {
+    let socket: SocketAddr = v.listen.parse()?;
+    let listen = match v.proto.as_ref() {
+        "http" => VHostListen::Http(socket),
+        "tls" => VHostListen::Tls(socket),
+        proto => Err(anyhow!("Unsupported vhost protocol {}", proto))?,
+    };
+    let vhost = VHost {
+        host: v.host.to_ascii_lowercase(),
+        client: ClientId::from(&v.client),
+        port: v.port.unwrap_or_else(|| socket.port()),
+    };
+    Ok((listen, vhost))
+}
+
+/// What `of` each [[vhost]] gives for its listener, on one host at most
+fn per_vhost<T>(
+    conf: &Conf,
+    of: impl Fn(&config::VHost) -> Result<Option<T>>,
+) -> Result<Vec<(VHostListen, T)>> {
+    let mut given: Vec<(VHostListen, T)> = vec![];
+    for v in &conf.vhost {
+        if let Some(value) = of(v)? {
+            let listen = vhost_mapper(v)?.0;
+            if given.iter().any(|(l, _)| *l == listen) {
+                Err(anyhow!("Access or limits of vhost {} given twice", listen))?;
+            }
+            given.push((listen, value));
+        }
+    }
+    Ok(given)
+}
+
+fn auth_mapper(a: &config::Auth) -> (ClientId, String) {
+    (ClientId::from(&a.client), a.secret.clone())
+}
+
+fn policy_mapper(a: &config::Auth) -> Result<(ClientId, Policy)> {
+    let mut ports = vec![];
+    for allow in &a.allow {
+        let range = match allow.split_once('-') {
+            Some((first, last)) => first.parse()?..=last.parse()?,
+            None => allow.parse()?..=allow.parse()?,
+        };
+        if range.is_empty() {
+            return Err(anyhow!("Reversed port range {}", allow));
+        }
+        ports.push(range);
+    }
+    let policy = Policy {
+        ports,
+        max_listen: a.max_listen.unwrap_or(usize::MAX),
+    };
+    Ok((ClientId::from(&a.client), policy))
+}
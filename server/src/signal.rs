@@ -0,0 +1,37 @@
+use crate::error::err_exit;
+use anyhow::Result;
+use log::warn;
+use shadow_peer::server::Shutdown;
+use signal_hook::consts::SIGHUP;
+use signal_hook::consts::SIGINT;
+use signal_hook::consts::SIGTERM;
+use signal_hook::iterator::Signals;
+use std::thread;
+
+/// Shut down on SIGTERM or SIGINT, exit at once on a second one. Call
+/// `reload` on SIGHUP.
+pub fn watch<F>(shutdown: Shutdown, reload: F) -> Result<()>
+where
+    F: Fn() -> Result<()> + Send + 'static,
+{
+    let mut signals = Signals::new([SIGTERM, SIGINT, SIGHUP])?;
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            if signal == SIGHUP {
+                match reload() {
+                    Ok(()) => warn!(target: "shadow-peer", "Signal {}, config reloaded", signal),
+                    Err(e) => {
+                        warn!(target: "shadow-peer", "Signal {}, reload failed: {}", signal, e)
+                    }
+                }
+                continue;
+            }
+            if shutdown.is_shutdown() {
+                err_exit(1, format!("Signal {}, exit now", signal));
+            }
+            warn!(target: "shadow-peer", "Signal {}, shutting down", signal);
+            shutdown.shutdown();
+        }
+    });
+    Ok(())
+}
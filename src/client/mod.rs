@@ -1,98 +1,911 @@
+use crate::dial;
 use crate::error::Error;
 use crate::error::Result;
+use crate::heartbeat::Heartbeat;
+use crate::metrics;
+use crate::metrics::Counter;
+use crate::metrics::Metrics;
+use crate::mux::MuxStream;
+use crate::mux::Session;
+use crate::protocol::auth;
+use crate::protocol::auth::Token;
+use crate::protocol::caps;
 use crate::protocol::net_proto::Establish;
-use crate::protocol::net_proto::TcpEstablish;
-use crate::protocol::read_protocol;
+pub use crate::protocol::net_proto::Proto;
+pub use crate::protocol::net_proto::Remote;
+use crate::protocol::protocol_stream;
+use crate::protocol::read_protocol_timeout;
 use crate::protocol::write_protocol;
 pub use crate::protocol::ClientId;
+use crate::protocol::Hello;
 use crate::protocol::Protocol;
-use crate::protocol::CURRENT_VERSION;
+use crate::protocol::BASE_VERSION;
+use crate::protocol::VERSIONS;
+use crate::proxy;
+pub use crate::proxy::ProxyProtocol;
+pub use crate::reload::Reload;
+use crate::shutdown::SessionGuard;
+pub use crate::shutdown::Shutdown;
+use crate::shutdown::DRAIN_TMOUT;
+use crate::stream;
+use crate::stream::BoxStream;
+use crate::stream::Counted;
+pub use crate::timeouts::BadTimeouts;
+pub use crate::timeouts::TimeoutSecs;
+pub use crate::timeouts::Timeouts;
+use crate::tls::TlsConnector;
+use crate::utils::Backoff;
 use async_std::io;
+use async_std::net::TcpListener;
 use async_std::net::TcpStream;
+use async_std::net::UdpSocket;
+use async_std::stream::StreamExt;
+use async_std::sync::Arc;
 use async_std::task;
+use futures::channel::mpsc;
+use futures::channel::mpsc::UnboundedReceiver;
+use futures::channel::mpsc::UnboundedSender;
+use futures::channel::oneshot;
+use futures::future::BoxFuture;
+use futures::future::Fuse;
+use futures::future::Shared;
+use futures::AsyncReadExt;
+use futures::AsyncWrite;
+use futures::AsyncWriteExt;
 use futures::FutureExt;
 use futures_timer::Delay;
 use log::warn;
 use std::collections::HashMap;
 use std::net::SocketAddr;
+use std::result::Result as StdResult;
+use std::sync::Mutex;
+use std::sync::RwLock;
 use std::time::Duration;
+use std::time::Instant;
+
+mod pool;
+mod udp;
+
+/// Sessions up for so long reset the backoff and attempts
+const STABLE: Duration = Duration::from_secs(30);
+
+type PortMap = Arc<RwLock<HashMap<(Proto, u16), Dest>>>;
+type OnState = Arc<dyn Fn(&State) + Send + Sync>;
 
 pub struct Client {
     client_id: ClientId,
-    port_map: HashMap<u16, SocketAddr>,
-    server: SocketAddr,
+    multiplex: bool,
+    pool: usize,
+    port_map: PortMap,
+    remote: Vec<Remote>,
+    secret: String,
+    /// In order of priority
+    servers: Vec<Upstream>,
+    failover: Failover,
+    simultaneous: usize,
+    /// Servers connected by the links of a running client
+    busy: Arc<Mutex<Vec<bool>>>,
+    /// Connected or tried last
+    server: Upstream,
+    timeouts: Timeouts,
+    shutdown: Shutdown,
+    drain: Duration,
+    reload: UnboundedSender<Settings>,
+    reloaded: Option<UnboundedReceiver<Settings>>,
+    metrics: Metrics,
+    metrics_listen: Option<SocketAddr>,
+    /// Failed connections in a row before giving up
+    max_attempts: Option<u32>,
+    on_state: Option<OnState>,
+    state: State,
+}
+
+/// Connection state of each link of a running client
+#[derive(Clone, Debug, PartialEq)]
+pub enum State {
+    /// Connecting and logging in to the server
+    Connecting(String),
+    /// Logged in
+    Connected(String),
+    /// The connection to `server` failed or was lost, the next server is
+    /// tried after `delay`.
+    Retrying {
+        server: String,
+        error: String,
+        attempt: u32,
+        delay: Duration,
+    },
+    /// Shut down, or given up after the error
+    Stopped(Option<String>),
+}
+
+/// Which server a link connects to next
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Failover {
+    /// The first one free in the order given, starting over once a
+    /// connection is lost
+    Priority,
+    /// The one after the last tried
+    RoundRobin,
+}
+
+/// Settings of a client which may change while it runs
+#[derive(Clone, Default)]
+pub struct Settings {
+    pub port_map: Vec<(Proto, u16, Dest)>,
+    /// Listeners registered at the server
+    pub remote: Vec<Remote>,
+}
+
+/// A local service visitors are mapped to
+#[derive(Clone, Debug, PartialEq)]
+pub struct Dest {
+    /// `host:port`, resolved on each connection
+    pub addr: String,
+    /// Header telling a TCP service the address of the visitor, if any
+    pub proxy: Option<ProxyProtocol>,
+}
+
+impl Dest {
+    pub fn new(addr: String) -> Dest {
+        Dest { addr, proxy: None }
+    }
+}
+
+/// Transport of both control and worker connections to the server
+#[derive(Clone)]
+pub enum Transport {
+    Tcp,
+    /// TLS verified against the connector's CA, with the server name
+    Tls(TlsConnector, String),
+}
+
+/// Agreed with the server at login
+struct Login {
+    version: u8,
+    caps: u32,
+    pool: Option<Token>,
+    /// Most connections the server parks, 0 if unknown
+    max_pool: u32,
+}
+
+/// Shared by the parked connections of one control session
+struct Parking {
+    server: Upstream,
+    client_id: ClientId,
+    token: Token,
+    port_map: PortMap,
+    closed: Shared<oneshot::Receiver<()>>,
+    shutdown: Shutdown,
+    metrics: Metrics,
+}
+
+#[derive(Clone)]
+struct Upstream {
+    /// `host:port`, resolved on each connection
+    addr: String,
+    transport: Transport,
+    timeouts: Timeouts,
 }
 
 impl Client {
+    /// `server` and the local services of `port_map` are `host:port`,
+    /// resolved on each connection.
     pub fn new(
-        server: SocketAddr,
+        server: String,
+        transport: Transport,
         client_id: ClientId,
-        port_map: Vec<(u16, SocketAddr)>,
+        secret: String,
+        port_map: Vec<(Proto, u16, Dest)>,
     ) -> Client {
-        let port_map = port_map.into_iter().collect();
+        let (reload, reloaded) = mpsc::unbounded();
+        let server = Upstream {
+            addr: server,
+            transport,
+            timeouts: Timeouts::default(),
+        };
         Client {
             client_id,
-            port_map,
+            multiplex: false,
+            pool: 0,
+            port_map: Arc::new(RwLock::new(port_map_of(port_map))),
+            remote: vec![],
+            secret,
+            servers: vec![server.clone()],
+            failover: Failover::Priority,
+            simultaneous: 1,
+            busy: Default::default(),
             server,
+            timeouts: Timeouts::default(),
+            shutdown: Shutdown::new(),
+            drain: Duration::from_secs(DRAIN_TMOUT),
+            reload,
+            reloaded: Some(reloaded),
+            metrics: Metrics::default(),
+            metrics_listen: None,
+            max_attempts: None,
+            on_state: None,
+            state: State::Stopped(None),
         }
     }
 
-    pub async fn run(mut self) {
-        loop {
-            if let Err(e) = self.run_impl().await {
-                warn!(target: "shadow-peer", "{}", e);
-                Delay::new(Duration::from_secs(3)).await;
+    /// Ask the server to carry all visitor streams over the control
+    /// connection, instead of one worker connection per stream.
+    pub fn multiplex(mut self, enable: bool) -> Client {
+        self.multiplex = enable;
+        self
+    }
+
+    /// Keep `size` idle worker connections parked at the server, so visitors
+    /// need not wait for a new connection. Unused if multiplexed.
+    pub fn pool(mut self, size: usize) -> Client {
+        self.pool = size;
+        self
+    }
+
+    /// Ask the server to open these listeners at login, instead of relying
+    /// on its own configuration. Visitors are still mapped by `port_map`.
+    pub fn register(mut self, remote: Vec<Remote>) -> Client {
+        self.remote = remote;
+        self
+    }
+
+    /// Handle to stop the client once running
+    pub fn shutdown_handle(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+
+    /// Handle to change the `Settings` once running, the connection to the
+    /// server is kept unless it must log in again to register.
+    pub fn reload_handle(&self) -> Reload<Settings> {
+        Reload::new(self.reload.clone())
+    }
+
+    /// Export metrics for Prometheus on `listen`
+    pub fn metrics(mut self, listen: SocketAddr) -> Client {
+        self.metrics_listen = Some(listen);
+        self
+    }
+
+    /// Time given to in-flight streams once shut down
+    pub fn drain(mut self, deadline: Duration) -> Client {
+        self.drain = deadline;
+        self
+    }
+
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Client {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Fail over to `server` too, after those given so far
+    pub fn fallback(mut self, server: String, transport: Transport) -> Client {
+        self.servers.push(Upstream {
+            addr: server,
+            transport,
+            timeouts: Timeouts::default(),
+        });
+        self
+    }
+
+    pub fn failover(mut self, failover: Failover) -> Client {
+        self.failover = failover;
+        self
+    }
+
+    /// Stay connected to `links` servers at once, each serving the same
+    /// port maps. A server may hold several connections of a client, but
+    /// the links are there to outlive a lost server, so each one keeps to a
+    /// server the others are not connected to.
+    pub fn simultaneous(mut self, links: usize) -> Client {
+        self.simultaneous = links;
+        self
+    }
+
+    /// Give up after `attempts` failed connections in a row, retry forever
+    /// otherwise.
+    pub fn max_attempts(mut self, attempts: u32) -> Client {
+        self.max_attempts = Some(attempts);
+        self
+    }
+
+    /// Call `f` on each change of the connection `State`
+    pub fn on_state<F>(mut self, f: F) -> Client
+    where
+        F: Fn(&State) + Send + Sync + 'static,
+    {
+        self.on_state = Some(Arc::new(f));
+        self
+    }
+
+    /// Serve until shut down, reconnect whenever the server is lost. Fail
+    /// with the last error once every link is out of attempts.
+    pub async fn run(mut self) -> Result<()> {
+        let shutdown = self.shutdown.clone();
+        let mut reloaded = self.reloaded.take().unwrap();
+        let exporter = Shutdown::new();
+        if let Some(listen) = self.metrics_listen {
+            match TcpListener::bind(listen).await {
+                Ok(tcp) => {
+                    let metrics = self.metrics.clone();
+                    task::spawn(metrics::serve(tcp, metrics, exporter.clone()));
+                }
+                Err(e) => warn!(target: "shadow-peer", "Metrics listen {}: {}", listen, e),
             }
         }
+        let timeouts = self.timeouts;
+        for server in &mut self.servers {
+            server.timeouts = timeouts;
+        }
+        *self.busy.lock().unwrap() = vec![false; self.servers.len()];
+        let mut links = vec![];
+        let mut senders = vec![];
+        // Extra links would connect to a server held by another already
+        for _ in 0..self.simultaneous.clamp(1, self.servers.len()) {
+            let (send, recv) = mpsc::unbounded();
+            links.push(self.link(recv).keep());
+            senders.push(send);
+        }
+        // Each link registers its listeners again on reload
+        let fan_out = async {
+            while let Some(settings) = reloaded.next().await {
+                for send in &senders {
+                    let _ = send.unbounded_send(settings.clone());
+                }
+            }
+        };
+        let ended = futures::select! {
+            ended = futures::future::join_all(links).fuse() => ended,
+            _ = fan_out.fuse() => vec![],
+        };
+        // Worker connections may outlive the control connection
+        let left = shutdown.drain(self.drain).await;
+        if left > 0 {
+            warn!(target: "shadow-peer", "Shutdown with {} streams left", left);
+        }
+        exporter.shutdown();
+        let failed = ended.into_iter().filter_map(|r| r.err()).last();
+        let r = match (failed, shutdown.is_shutdown()) {
+            (Some(e), false) => Err(e),
+            _ => Ok(()),
+        };
+        let error = r.as_ref().err().map(|e| e.to_string());
+        self.set_state(State::Stopped(error));
+        r
     }
 
-    async fn run_impl(&mut self) -> Result<()> {
-        let ctrl = TcpStream::connect(self.server);
-        let mut ctrl = io::timeout(tmout(), ctrl).await?;
-        let ctrl = &mut ctrl;
-        let hello = Protocol::ClientId(self.client_id.clone());
-        write_wrap(ctrl, &hello).await?;
-        loop {
-            let proto = async_std::future::timeout(tmout(), read_protocol(ctrl));
-            match proto.await?? {
-                Protocol::Ping(ts) => write_wrap(ctrl, &Protocol::Ping(ts)).await?,
-                Protocol::Establish(Establish::Tcp(est)) => {
-                    let dest = match self.port_map.get(&est.dest.port()) {
-                        Some(dest) => *dest,
-                        None => continue, //TODO ignore proto
+    /// A copy of this client to keep one control connection, with its own
+    /// `reloaded`
+    fn link(&self, reloaded: UnboundedReceiver<Settings>) -> Client {
+        Client {
+            client_id: self.client_id.clone(),
+            multiplex: self.multiplex,
+            pool: self.pool,
+            port_map: self.port_map.clone(),
+            remote: self.remote.clone(),
+            secret: self.secret.clone(),
+            servers: self.servers.clone(),
+            failover: self.failover,
+            simultaneous: self.simultaneous,
+            busy: self.busy.clone(),
+            server: self.server.clone(),
+            timeouts: self.timeouts,
+            shutdown: self.shutdown.clone(),
+            drain: self.drain,
+            reload: self.reload.clone(),
+            reloaded: Some(reloaded),
+            metrics: self.metrics.clone(),
+            metrics_listen: None,
+            max_attempts: self.max_attempts,
+            on_state: self.on_state.clone(),
+            state: State::Stopped(None),
+        }
+    }
+
+    /// Keep a control connection until shut down, or out of attempts
+    async fn keep(mut self) -> Result<()> {
+        let shutdown = self.shutdown.clone();
+        let mut reloaded = self.reloaded.take().unwrap();
+        let reconnects = self.metrics.counter(&metrics::RECONNECTS, &[]);
+        let timeouts = self.timeouts;
+        let mut backoff = Backoff::range(timeouts.reconnect, timeouts.reconnect_max);
+        let mut attempt = 0;
+        // Servers tried since one was connected
+        let mut tried = 0;
+        let mut next = 0;
+        while !shutdown.is_shutdown() {
+            let i = self.pick(next);
+            self.server = self.servers[i].clone();
+            self.set_state(State::Connecting(self.server.addr.clone()));
+            let start = Instant::now();
+            let session = self.run_impl(&mut reloaded).await;
+            self.busy.lock().unwrap()[i] = false;
+            let connected = self.state == State::Connected(self.server.addr.clone());
+            // Sessions dropped soon after login still back off
+            if connected && start.elapsed() >= STABLE {
+                backoff.reset();
+                attempt = 0;
+                tried = 0;
+            }
+            next = match (self.failover, connected) {
+                (Failover::Priority, true) => 0,
+                _ => i + 1,
+            };
+            let error = match session {
+                Ok(()) if shutdown.is_shutdown() => break,
+                Ok(()) => {
+                    warn!(target: "shadow-peer", "{}: Closed by the server", self.server.addr);
+                    String::from("closed by the server")
+                }
+                Err(e) => {
+                    warn!(target: "shadow-peer", "{}: {}", self.server.addr, e);
+                    attempt += 1;
+                    if self.max_attempts.map(|max| attempt >= max).unwrap_or(false) {
+                        warn!(target: "shadow-peer", "Given up after {} attempts", attempt);
+                        return Err(e);
+                    }
+                    e.to_string()
+                }
+            };
+            reconnects.inc();
+            tried += 1;
+            // Fail over at once, back off after a round of failures
+            let delay = match tried < self.servers.len() {
+                true => Duration::from_secs(0),
+                false => {
+                    tried = 0;
+                    backoff.jittered()
+                }
+            };
+            self.set_state(State::Retrying {
+                server: self.server.addr.clone(),
+                error,
+                attempt,
+                delay,
+            });
+            futures::select! {
+                _ = Delay::new(delay).fuse() => {},
+                _ = shutdown.wait().fuse() => {},
+            }
+        }
+        Ok(())
+    }
+
+    /// The first server not connected by another link, from `next` on.
+    /// There are no more links than servers.
+    fn pick(&self, next: usize) -> usize {
+        let mut busy = self.busy.lock().unwrap();
+        let n = busy.len();
+        let mut free = (next..next + n).map(|i| i % n).filter(|i| !busy[*i]);
+        let i = free.next().unwrap_or(next % n);
+        busy[i] = true;
+        i
+    }
+
+    fn set_state(&mut self, state: State) {
+        let connected = self.metrics.gauge(&metrics::CONNECTED, &[]);
+        match (&self.state, &state) {
+            (State::Connected(_), State::Connected(_)) => {}
+            (_, State::Connected(_)) => connected.add(1.0),
+            (State::Connected(_), _) => connected.add(-1.0),
+            _ => {}
+        }
+        if let Some(on_state) = &self.on_state {
+            on_state(&state);
+        }
+        self.state = state;
+    }
+
+    async fn run_impl(&mut self, reloaded: &mut UnboundedReceiver<Settings>) -> Result<()> {
+        while let Ok(Some(settings)) = reloaded.try_next() {
+            self.apply(settings);
+        }
+        let mut ctrl = self.server.connect().await?;
+        let login = self.login(&mut ctrl).await?;
+        self.set_state(State::Connected(self.server.addr.clone()));
+        let (reader, mut writer) = ctrl.split();
+        let (send, mut recv) = mpsc::unbounded();
+        let mux = match login.caps & caps::MULTIPLEX {
+            0 => None,
+            _ => Some(Session::new(send.clone())),
+        };
+        // Parked connections are closed once `closer` is dropped
+        let (closer, closed) = oneshot::channel();
+        let mut closer = Some(closer);
+        if let Some(token) = login.pool {
+            let parking = Arc::new(Parking {
+                server: self.server.clone(),
+                client_id: self.client_id.clone(),
+                token,
+                port_map: self.port_map.clone(),
+                closed: closed.shared(),
+                shutdown: self.shutdown.clone(),
+                metrics: self.metrics.clone(),
+            });
+            let size = match login.max_pool as usize {
+                max if max > 0 && self.pool > max => {
+                    warn!(target: "shadow-peer", "Pool of {} is over the server's {}", self.pool, max);
+                    max
+                }
+                _ => self.pool,
+            };
+            for _ in 0..size {
+                task::spawn(pool::park(parking.clone()));
+            }
+        }
+        let timeouts = self.timeouts;
+        let mut reader = Box::pin(protocol_stream(reader, timeouts.read));
+        // Servers without `caps::HEARTBEAT` only ping
+        let mut heartbeat = Heartbeat::new(timeouts.missed_pings);
+        let mut ping_timer = match login.caps & caps::HEARTBEAT {
+            0 => Fuse::terminated(),
+            _ => Delay::new(timeouts.ping).fuse(),
+        };
+        let shutdown = self.shutdown.clone();
+        let mut stop = Box::pin(shutdown.wait().fuse());
+        let mut drained: Fuse<BoxFuture<usize>> = Fuse::terminated();
+        let r = loop {
+            futures::select! {
+                proto = reader.next().fuse() => {
+                    let r = match proto {
+                        Some(proto) => {
+                            proto.and_then(|p| self.handle(p, &send, &mux, &mut heartbeat))
+                        }
+                        None => Ok(()),
+                    };
+                    if r.is_err() {
+                        break r;
+                    }
+                },
+                proto = recv.next().fuse() => {
+                    if let Some(proto) = proto {
+                        if let Err(e) = write_wrap(&mut writer, login.version, &proto).await {
+                            break Err(e);
+                        }
+                    }
+                },
+                _ = ping_timer => {
+                    let ping = match heartbeat.ping(false) {
+                        Some(ping) => ping,
+                        None => break Err(Error::Unresponsive(timeouts.missed_pings)),
+                    };
+                    if let Err(e) = write_wrap(&mut writer, login.version, &ping).await {
+                        break Err(e);
+                    }
+                    ping_timer = Delay::new(timeouts.ping).fuse();
+                },
+                settings = reloaded.next().fuse() => {
+                    let settings = match settings {
+                        Some(settings) => settings,
+                        None => continue,
                     };
-                    task::spawn(worker(self.server, dest, est));
+                    if !self.apply(settings) {
+                        continue;
+                    }
+                    if login.caps & caps::REGISTER == 0 {
+                        warn!(target: "shadow-peer", "Login again to register listeners");
+                        break Ok(());
+                    }
+                    let proto = Protocol::Register(self.remote.clone());
+                    if let Err(e) = write_wrap(&mut writer, login.version, &proto).await {
+                        break Err(e);
+                    }
+                },
+                _ = stop => {
+                    let goodbye = Protocol::Goodbye;
+                    if let Err(e) = write_wrap(&mut writer, login.version, &goodbye).await {
+                        break Err(e);
+                    }
+                    closer = None;
+                    let (shutdown, deadline) = (shutdown.clone(), self.drain);
+                    drained = async move { shutdown.drain(deadline).await }.boxed().fuse();
+                },
+                left = drained => {
+                    if left > 0 {
+                        warn!(target: "shadow-peer", "Shutdown with {} streams left", left);
+                    }
+                    break Ok(());
+                },
+            }
+        };
+        drop(closer);
+        if let Some(mux) = mux {
+            mux.close();
+        }
+        r
+    }
+
+    fn handle(
+        &self,
+        proto: Protocol,
+        send: &UnboundedSender<Protocol>,
+        mux: &Option<Arc<Session>>,
+        heartbeat: &mut Heartbeat,
+    ) -> Result<()> {
+        let proto = match mux {
+            Some(mux) => match mux.dispatch(proto) {
+                Some(proto) => proto,
+                None => return Ok(()),
+            },
+            None => proto,
+        };
+        let closing = self.shutdown.is_shutdown();
+        match (proto, mux) {
+            (Protocol::Ping(seq), _) => {
+                let _ = send.unbounded_send(Protocol::Ping(seq));
+            }
+            (Protocol::Heartbeat(seq, ts), _) => {
+                let _ = send.unbounded_send(Protocol::Pong(seq, ts));
+            }
+            (Protocol::Pong(seq, ts), _) => {
+                if let Some(rtt) = heartbeat.pong(seq, ts) {
+                    let jitter = heartbeat.jitter();
+                    self.metrics
+                        .gauge(&metrics::PING_RTT, &[])
+                        .set(rtt.as_secs_f64());
+                    self.metrics
+                        .gauge(&metrics::PING_JITTER, &[])
+                        .set(jitter.as_secs_f64());
+                }
+            }
+            (Protocol::Goodbye, _) => {
+                warn!(target: "shadow-peer", "Server is shutting down");
+            }
+            (Protocol::Registered(result), _) => self.registered(result),
+            (Protocol::Establish(..), _) if closing => {}
+            (Protocol::Establish(est, token), _) => {
+                if let Some(dest) = self.dest(&est) {
+                    let session = self.shutdown.enter();
+                    let meters = Meters::new(&self.metrics, &est, &dest.addr);
+                    let server = self.server.clone();
+                    task::spawn(worker(server, dest, est, token, meters, session));
                 }
+            }
+            (Protocol::Open(sid, _), Some(mux)) if closing => mux.reject(sid),
+            (Protocol::Open(sid, est), Some(mux)) => match self.dest(&est) {
+                Some(dest) => {
+                    let stream = mux.accept(sid);
+                    let session = self.shutdown.enter();
+                    let meters = Meters::new(&self.metrics, &est, &dest.addr);
+                    let timeouts = self.timeouts;
+                    task::spawn(mux_worker(stream, dest, est, meters, timeouts, session));
+                }
+                None => mux.reject(sid),
+            },
+            (p, _) => return Err(Error::InvalidOperation(format!("{:?}", p))),
+        }
+        Ok(())
+    }
+
+    fn dest(&self, est: &Establish) -> Option<Dest> {
+        let port_map = self.port_map.read().unwrap();
+        port_map.get(&(est.proto(), est.dest().port())).cloned()
+    }
+
+    /// Apply `settings`, return whether the listeners to register changed
+    fn apply(&mut self, settings: Settings) -> bool {
+        *self.port_map.write().unwrap() = port_map_of(settings.port_map);
+        if self.remote == settings.remote {
+            return false;
+        }
+        self.remote = settings.remote;
+        true
+    }
+
+    async fn login(&self, ctrl: &mut BoxStream) -> Result<Login> {
+        let mut caps = match (self.multiplex, self.pool) {
+            (true, _) => caps::MULTIPLEX,
+            (false, 0) => 0,
+            (false, _) => caps::POOL,
+        };
+        if !self.remote.is_empty() {
+            caps |= caps::REGISTER;
+        }
+        caps |= caps::HEARTBEAT;
+        let hello = Hello {
+            caps,
+            versions: VERSIONS.to_vec(),
+            max_pool: 0,
+        };
+        write_wrap(ctrl, BASE_VERSION, &Protocol::Hello(hello)).await?;
+        let (version, hello) = match read_protocol_timeout(ctrl, self.timeouts.read).await? {
+            Protocol::Hello(hello) => match hello.versions.as_slice() {
+                [ver] if VERSIONS.contains(ver) => (*ver, hello),
+                [] => (BASE_VERSION, hello),
+                _ => return Err(Error::InvalidOperation(format!("{:?}", hello))),
+            },
+            p => return Err(Error::InvalidOperation(format!("{:?}", p))),
+        };
+        let (caps, max_pool) = (hello.caps, hello.max_pool);
+        let hello = Protocol::ClientId(self.client_id.clone());
+        write_wrap(ctrl, version, &hello).await?;
+        let nonce = match read_protocol_timeout(ctrl, self.timeouts.read).await? {
+            Protocol::Challenge(nonce) => nonce,
+            p => return Err(Error::InvalidOperation(format!("{:?}", p))),
+        };
+        let sign = auth::sign(&self.secret, &self.client_id, &nonce);
+        write_wrap(ctrl, version, &Protocol::Response(sign)).await?;
+        let pool = match caps & caps::POOL {
+            0 => None,
+            _ => match read_protocol_timeout(ctrl, self.timeouts.read).await? {
+                Protocol::Pool(token) => Some(token),
                 p => return Err(Error::InvalidOperation(format!("{:?}", p))),
+            },
+        };
+        if caps & caps::REGISTER != 0 {
+            self.register_remote(ctrl, version).await?;
+        }
+        Ok(Login {
+            version,
+            caps,
+            pool,
+            max_pool,
+        })
+    }
+    async fn register_remote(&self, ctrl: &mut BoxStream, version: u8) -> Result<()> {
+        let proto = Protocol::Register(self.remote.clone());
+        write_wrap(ctrl, version, &proto).await?;
+        match read_protocol_timeout(ctrl, self.timeouts.read).await? {
+            Protocol::Registered(result) => self.registered(result),
+            p => return Err(Error::InvalidOperation(format!("{:?}", p))),
+        };
+        Ok(())
+    }
+
+    fn registered(&self, result: Vec<StdResult<SocketAddr, String>>) {
+        for (remote, r) in self.remote.iter().zip(result) {
+            if let Err(e) = r {
+                warn!(target: "shadow-peer", "Register {:?} {}: {}", remote.proto, remote.port, e);
+            }
+        }
+    }
+}
+
+impl Upstream {
+    async fn connect(&self) -> Result<BoxStream> {
+        let tmout = self.timeouts.connect;
+        let tcp = io::timeout(tmout, dial::tcp(&self.addr)).await?;
+        match &self.transport {
+            Transport::Tcp => Ok(Box::new(tcp)),
+            Transport::Tls(tls, domain) => {
+                let tls = io::timeout(tmout, tls.connect(domain, tcp)).await?;
+                Ok(Box::new(tls))
             }
         }
     }
 }
 
-async fn worker(server: SocketAddr, dest: SocketAddr, est: TcpEstablish) {
-    let _ = worker_impl(server, dest, est).await;
+async fn worker(
+    server: Upstream,
+    dest: Dest,
+    est: Establish,
+    token: Token,
+    meters: Meters,
+    _session: SessionGuard,
+) {
+    let _ = worker_impl(server, dest, est, token, &meters).await;
 }
 
-async fn worker_impl(server: SocketAddr, dest: SocketAddr, est: TcpEstablish) -> Result<()> {
-    let dest = TcpStream::connect(dest).await?;
-    let mut server = TcpStream::connect(server).await?;
-    let hello = Protocol::Establish(Establish::Tcp(est));
-    write_wrap(&mut server, &hello).await?;
+async fn worker_impl(
+    server: Upstream,
+    dest: Dest,
+    est: Establish,
+    token: Token,
+    meters: &Meters,
+) -> Result<()> {
+    let timeouts = &server.timeouts;
+    let local = Local::connect(&est, &dest, meters, timeouts).await?;
+    let mut stream = server.connect().await?;
+    let hello = Protocol::Establish(est, token);
+    write_wrap(&mut stream, BASE_VERSION, &hello).await?;
 
     // Sync
-    let (dr, dw) = &mut (&dest, &dest);
-    let (sr, sw) = &mut (&server, &server);
-    futures::select! {
-        r = io::copy(dr, sw).fuse() => r?,
-        r = io::copy(sr, dw).fuse() => r?,
-    };
-    Ok(())
+    local.sync(stream, meters, timeouts).await
+}
+
+async fn mux_worker(
+    stream: MuxStream,
+    dest: Dest,
+    est: Establish,
+    meters: Meters,
+    timeouts: Timeouts,
+    _session: SessionGuard,
+) {
+    if let Ok(local) = Local::connect(&est, &dest, &meters, &timeouts).await {
+        let _ = local.sync(Box::new(stream), &meters, &timeouts).await;
+    }
+}
+
+/// Metrics of the sessions of one port map
+struct Meters {
+    bytes_in: Counter,
+    bytes_out: Counter,
+    opened: Counter,
+    failed: Counter,
+}
+
+impl Meters {
+    fn new(metrics: &Metrics, est: &Establish, dest: &str) -> Meters {
+        let port = format!("{} {}", est.proto(), est.dest().port());
+        let labels = [("port", port.as_str()), ("dest", dest)];
+        let bytes = |direction| {
+            let labels = [labels[0], labels[1], ("direction", direction)];
+            metrics.counter(&metrics::LOCAL_BYTES, &labels)
+        };
+        let sessions = |result| {
+            let labels = [labels[0], labels[1], ("result", result)];
+            metrics.counter(&metrics::SESSIONS, &labels)
+        };
+        Meters {
+            bytes_in: bytes("in"),
+            bytes_out: bytes("out"),
+            opened: sessions("opened"),
+            failed: sessions("failed"),
+        }
+    }
+}
+
+/// Connection to the local service
+enum Local {
+    Tcp(TcpStream),
+    Udp(UdpSocket),
+}
+
+impl Local {
+    async fn connect(
+        est: &Establish,
+        dest: &Dest,
+        meters: &Meters,
+        timeouts: &Timeouts,
+    ) -> Result<Local> {
+        let r = Local::connect_impl(est, dest, timeouts).await;
+        match &r {
+            Ok(_) => meters.opened.inc(),
+            Err(_) => meters.failed.inc(),
+        }
+        r
+    }
+
+    /// Connect the local service of `est`, a TCP one is told the visitor's
+    /// address first if configured.
+    async fn connect_impl(est: &Establish, dest: &Dest, timeouts: &Timeouts) -> Result<Local> {
+        match est.proto() {
+            Proto::Tcp => {
+                let mut tcp = dial::tcp(&dest.addr).await?;
+                timeouts.keepalive(&tcp);
+                if let Some(version) = dest.proxy {
+                    let header = proxy::header(version, est.src(), est.dest());
+                    tcp.write_all(&header).await?;
+                }
+                Ok(Local::Tcp(tcp))
+            }
+            Proto::Udp => Ok(Local::Udp(dial::udp(&dest.addr).await?)),
+        }
+    }
+
+    async fn sync(self, server: BoxStream, meters: &Meters, timeouts: &Timeouts) -> Result<()> {
+        match self {
+            Local::Tcp(tcp) => {
+                let (bytes_out, bytes_in) = (meters.bytes_out.clone(), meters.bytes_in.clone());
+                let tcp = Counted::new(tcp, bytes_out, bytes_in);
+                stream::pipe(Box::new(tcp), server, timeouts.idle).await?
+            }
+            Local::Udp(udp) => udp::relay(udp, server, meters, timeouts.udp_idle).await?,
+        }
+        Ok(())
+    }
 }
 
-async fn write_wrap(s: &mut TcpStream, proto: &Protocol) -> Result<()> {
-    write_protocol(s, CURRENT_VERSION, proto).await
+fn port_map_of(port_map: Vec<(Proto, u16, Dest)>) -> HashMap<(Proto, u16), Dest> {
+    port_map
+        .into_iter()
+        .map(|(proto, port, addr)| ((proto, port), addr))
+        .collect()
 }
 
-fn tmout() -> Duration {
-    Duration::from_secs(10)
+async fn write_wrap<W>(s: &mut W, version: u8, proto: &Protocol) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    write_protocol(s, version, proto).await
 }
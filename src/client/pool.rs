@@ -0,0 +1,81 @@
+use super::write_wrap;
+use super::Dest;
+use super::Local;
+use super::Meters;
+use super::Parking;
+use super::Timeouts;
+use crate::error::Error;
+use crate::error::Result;
+use crate::protocol::net_proto::Establish;
+use crate::protocol::read_protocol_timeout;
+use crate::protocol::Protocol;
+use crate::protocol::BASE_VERSION;
+use crate::protocol::PARK_IDLE;
+use crate::shutdown::SessionGuard;
+use crate::stream::BoxStream;
+use async_std::sync::Arc;
+use async_std::task;
+use futures::FutureExt;
+use futures_timer::Delay;
+
+/// Park a worker connection at the server, once it is used park a new one in
+/// its place. Stop when the control session is closed.
+pub(super) async fn park(p: Arc<Parking>) {
+    let mut closed = p.closed.clone();
+    loop {
+        let r = futures::select! {
+            r = park_impl(&p).fuse() => r,
+            _ = closed => return,
+        };
+        let (stream, est) = match r {
+            Ok(Some(r)) => r,
+            // Idle for long, parked again
+            Ok(None) => continue,
+            Err(_) => {
+                futures::select! {
+                    _ = Delay::new(p.server.timeouts.reconnect).fuse() => continue,
+                    _ = closed => return,
+                }
+            }
+        };
+        let dest = p
+            .port_map
+            .read()
+            .unwrap()
+            .get(&(est.proto(), est.dest().port()))
+            .cloned();
+        if let Some(dest) = dest {
+            let session = p.shutdown.enter();
+            let meters = Meters::new(&p.metrics, &est, &dest.addr);
+            let timeouts = p.server.timeouts;
+            task::spawn(worker(stream, dest, est, meters, timeouts, session));
+        }
+    }
+}
+
+async fn worker(
+    stream: BoxStream,
+    dest: Dest,
+    est: Establish,
+    meters: Meters,
+    timeouts: Timeouts,
+    _session: SessionGuard,
+) {
+    if let Ok(local) = Local::connect(&est, &dest, &meters, &timeouts).await {
+        let _ = local.sync(stream, &meters, &timeouts).await;
+    }
+}
+
+/// Park a connection until the server uses it, None once it was idle for
+/// `PARK_IDLE`
+async fn park_impl(p: &Parking) -> Result<Option<(BoxStream, Establish)>> {
+    let mut stream = p.server.connect().await?;
+    let hello = Protocol::Park(p.client_id.clone(), p.token);
+    write_wrap(&mut stream, BASE_VERSION, &hello).await?;
+    match read_protocol_timeout(&mut stream, PARK_IDLE).await {
+        Ok(Protocol::Establish(est, _)) => Ok(Some((stream, est))),
+        Ok(p) => Err(Error::InvalidOperation(format!("{:?}", p))),
+        Err(Error::Timeout(_)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
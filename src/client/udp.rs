@@ -0,0 +1,57 @@
+use super::Meters;
+use crate::error::Result;
+use crate::protocol::datagram::datagram_stream;
+use crate::protocol::datagram::write_datagram;
+use crate::stream::BoxStream;
+use async_std::net::UdpSocket;
+use async_std::stream::StreamExt;
+use futures::AsyncReadExt;
+use futures::FutureExt;
+use futures_timer::Delay;
+use std::time::Duration;
+
+/// Relay datagrams between the connected `socket` and the worker connection,
+/// until either side closes or the session is idle.
+pub(super) async fn relay(
+    socket: UdpSocket,
+    server: BoxStream,
+    meters: &Meters,
+    idle: Duration,
+) -> Result<()> {
+    let (reader, mut writer) = server.split();
+    let mut frames = Box::pin(datagram_stream(reader));
+    let mut recv = Box::pin(recv_stream(&socket));
+    loop {
+        let idle = Delay::new(idle);
+        futures::select! {
+            frame = frames.next().fuse() => match frame {
+                Some(frame) => {
+                    let frame = frame?;
+                    meters.bytes_in.add(frame.len() as u64);
+                    socket.send(&frame).await?;
+                }
+                None => return Ok(()),
+            },
+            data = recv.next().fuse() => match data {
+                Some(data) => {
+                    let data = data?;
+                    meters.bytes_out.add(data.len() as u64);
+                    write_datagram(&mut writer, &data).await?
+                }
+                None => return Ok(()),
+            },
+            _ = idle.fuse() => return Ok(()),
+        }
+    }
+}
+
+fn recv_stream(socket: &UdpSocket) -> impl futures::Stream<Item = Result<Vec<u8>>> + '_ {
+    futures::stream::unfold(socket, |socket| async move {
+        let mut buf = vec![0u8; u16::MAX as usize];
+        let data = socket.recv(&mut buf).await.map(|len| {
+            buf.truncate(len);
+            buf
+        });
+        Some((data.map_err(From::from), socket))
+    })
+}
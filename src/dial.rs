@@ -0,0 +1,83 @@
+//! Connect to `host:port`, resolved on each call
+
+use async_std::io;
+use async_std::net::TcpStream;
+use async_std::net::ToSocketAddrs;
+use async_std::net::UdpSocket;
+use futures::future::Fuse;
+use futures::stream::FuturesUnordered;
+use futures::FutureExt;
+use futures::StreamExt;
+use futures_timer::Delay;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// Before racing the next address against those still connecting
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Addresses of `host`, alternating families starting with the first one
+/// resolved, as RFC 8305.
+pub(crate) async fn resolve(host: &str) -> io::Result<Vec<SocketAddr>> {
+    let addrs: Vec<_> = host.to_socket_addrs().await?.collect();
+    let first = match addrs.first() {
+        Some(addr) => addr.is_ipv4(),
+        None => return Err(not_found(host)),
+    };
+    let (mut same, mut other): (Vec<_>, Vec<_>) =
+        addrs.into_iter().partition(|a| a.is_ipv4() == first);
+    same.reverse();
+    other.reverse();
+    let mut sorted = vec![];
+    while !same.is_empty() || !other.is_empty() {
+        sorted.extend(same.pop());
+        sorted.extend(other.pop());
+    }
+    Ok(sorted)
+}
+
+/// Connect to the addresses of `host` in turn, starting the next attempt
+/// once the previous one failed or took `ATTEMPT_DELAY`, the first one
+/// connected wins.
+pub(crate) async fn tcp(host: &str) -> io::Result<TcpStream> {
+    let mut addrs = resolve(host).await?.into_iter();
+    let mut pending = FuturesUnordered::new();
+    let mut error = None;
+    loop {
+        let mut delay = match addrs.next() {
+            Some(addr) => {
+                pending.push(TcpStream::connect(addr));
+                Delay::new(ATTEMPT_DELAY).fuse()
+            }
+            None => Fuse::terminated(),
+        };
+        if pending.is_empty() {
+            return Err(error.unwrap_or_else(|| not_found(host)));
+        }
+        futures::select! {
+            r = pending.select_next_some() => match r {
+                Ok(tcp) => return Ok(tcp),
+                Err(e) => error = Some(e),
+            },
+            _ = delay => {},
+        }
+    }
+}
+
+/// An UDP socket connected to the first address of `host`
+pub(crate) async fn udp(host: &str) -> io::Result<UdpSocket> {
+    let dest = resolve(host).await?[0];
+    let local: SocketAddr = match dest {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(dest).await?;
+    Ok(socket)
+}
+
+fn not_found(host: &str) -> io::Error {
+    let msg = format!("No address for {}", host);
+    io::Error::new(io::ErrorKind::NotFound, msg)
+}
@@ -1,5 +1,3 @@
-use log::error;
-use std::fmt::Display;
 use thiserror::Error;
 
 pub type Error = ShadowPeerError;
@@ -16,15 +14,16 @@ pub enum ShadowPeerError {
     InvalidOperation(String),
     #[error("Unexpect listen fail on {0} port {1}")]
     ListenFail(&'static str, u32),
+    #[error("rejected: {0}")]
+    Rejected(String),
     #[error("serde json: {0}")]
     SerdeJson(#[from] serde_json::Error),
+    #[error("tls: {0}")]
+    Tls(String),
     #[error("timeout: {0}")]
     Timeout(#[from] TimeoutError),
+    #[error("no pong to the last {0} pings")]
+    Unresponsive(u32),
     #[error("unsupported version {0}")]
     UnsupportedVersion(u8),
 }
-
-pub(crate) fn err_exit<S: Display>(code: i32, e: S) -> ! {
-    error!(target: "shadow-peer", "{}", e);
-    std::process::exit(code)
-}
@@ -0,0 +1,170 @@
+//! Ping / pong over the control connection. Each side pings on its own,
+//! `Heartbeat` and `Pong` carry a sequence number and the sender's
+//! monotonic clock in milliseconds, which comes back as is.
+
+use crate::protocol::Protocol;
+use once_cell::sync::Lazy;
+use std::time::Duration;
+use std::time::Instant;
+
+/// Milliseconds on a monotonic clock, since its first use
+pub(crate) fn now_ms() -> u64 {
+    static START: Lazy<Instant> = Lazy::new(Instant::now);
+    START.elapsed().as_millis() as u64
+}
+
+/// Pings sent on a control connection and the pongs answered
+pub(crate) struct Heartbeat {
+    /// Pings left unanswered before the connection is given up
+    max_missed: u32,
+    /// Of the last ping sent
+    seq: u32,
+    ts: u64,
+    /// Of the last ping answered
+    acked: u32,
+    last_pong: Option<Instant>,
+    rtt: Option<Duration>,
+    /// Smoothed difference of consecutive round trips, as RFC 3550
+    jitter: Duration,
+}
+
+impl Heartbeat {
+    pub(crate) fn new(max_missed: u32) -> Heartbeat {
+        Heartbeat {
+            max_missed,
+            seq: 0,
+            ts: 0,
+            acked: 0,
+            last_pong: None,
+            rtt: None,
+            jitter: Duration::from_secs(0),
+        }
+    }
+
+    /// The next ping, `Ping` for peers without `caps::HEARTBEAT`, or None
+    /// once `max_missed` pings went unanswered.
+    pub(crate) fn ping(&mut self, legacy: bool) -> Option<Protocol> {
+        if self.missed() >= self.max_missed {
+            return None;
+        }
+        self.seq = self.seq.wrapping_add(1);
+        self.ts = now_ms();
+        match legacy {
+            true => Some(Protocol::Ping(self.seq as u16)),
+            false => Some(Protocol::Heartbeat(self.seq, self.ts)),
+        }
+    }
+
+    /// Account the answer to ping `seq` sent at `ts`, return the round trip
+    pub(crate) fn pong(&mut self, seq: u32, ts: u64) -> Option<Duration> {
+        // Stale, or not a ping of ours
+        if seq.wrapping_sub(self.acked) > self.missed() || seq == self.acked {
+            return None;
+        }
+        self.acked = seq;
+        self.last_pong = Some(Instant::now());
+        let rtt = Duration::from_millis(now_ms().saturating_sub(ts));
+        if let Some(last) = self.rtt {
+            let diff = rtt.max(last) - rtt.min(last);
+            self.jitter = match diff > self.jitter {
+                true => self.jitter + (diff - self.jitter) / 16,
+                false => self.jitter - (self.jitter - diff) / 16,
+            };
+        }
+        self.rtt = Some(rtt);
+        Some(rtt)
+    }
+
+    /// Account a `Ping` echoed by a peer without `caps::HEARTBEAT`
+    pub(crate) fn echo(&mut self, seq: u16) -> Option<Duration> {
+        match seq == self.seq as u16 {
+            true => self.pong(self.seq, self.ts),
+            false => None,
+        }
+    }
+
+    /// Pings sent since the last one answered
+    pub(crate) fn missed(&self) -> u32 {
+        self.seq.wrapping_sub(self.acked)
+    }
+
+    pub(crate) fn last_pong(&self) -> Option<Instant> {
+        self.last_pong
+    }
+
+    pub(crate) fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    pub(crate) fn jitter(&self) -> Duration {
+        self.jitter
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn seq(ping: Option<Protocol>) -> (u32, u64) {
+        match ping {
+            Some(Protocol::Heartbeat(seq, ts)) => (seq, ts),
+            ping => panic!("{:?}", ping),
+        }
+    }
+
+    #[test]
+    fn missed_pings() {
+        let mut heartbeat = Heartbeat::new(3);
+        for missed in 0..3 {
+            assert_eq!(heartbeat.missed(), missed);
+            seq(heartbeat.ping(false));
+        }
+        assert!(heartbeat.ping(false).is_none());
+        assert!(heartbeat.last_pong().is_none());
+    }
+
+    #[test]
+    fn pong_acks_pings_before() {
+        let mut heartbeat = Heartbeat::new(3);
+        let (first, ts) = seq(heartbeat.ping(false));
+        let (second, _) = seq(heartbeat.ping(false));
+        assert_eq!(heartbeat.missed(), 2);
+        assert!(heartbeat.pong(second, ts).is_some());
+        assert_eq!(heartbeat.missed(), 0);
+        // Stale, answered already, or never sent
+        assert!(heartbeat.pong(first, ts).is_none());
+        assert!(heartbeat.pong(second, ts).is_none());
+        assert!(heartbeat.pong(second + 5, ts).is_none());
+        assert!(heartbeat.last_pong().is_some());
+    }
+
+    #[test]
+    fn round_trip_and_jitter() {
+        let mut heartbeat = Heartbeat::new(3);
+        let (seq1, ts1) = seq(heartbeat.ping(false));
+        thread::sleep(Duration::from_millis(20));
+        let rtt = heartbeat.pong(seq1, ts1).unwrap();
+        assert!(rtt >= Duration::from_millis(20));
+        assert_eq!(heartbeat.rtt(), Some(rtt));
+        assert_eq!(heartbeat.jitter(), Duration::from_secs(0));
+
+        let (seq2, ts2) = seq(heartbeat.ping(false));
+        thread::sleep(Duration::from_millis(60));
+        let next = heartbeat.pong(seq2, ts2).unwrap();
+        // A sixteenth of the difference of the round trips
+        assert_eq!(heartbeat.jitter(), (next - rtt) / 16);
+    }
+
+    #[test]
+    fn legacy_echo() {
+        let mut heartbeat = Heartbeat::new(3);
+        let seq = match heartbeat.ping(true) {
+            Some(Protocol::Ping(seq)) => seq,
+            ping => panic!("{:?}", ping),
+        };
+        assert!(heartbeat.echo(seq.wrapping_add(1)).is_none());
+        assert!(heartbeat.echo(seq).is_some());
+        assert_eq!(heartbeat.missed(), 0);
+    }
+}
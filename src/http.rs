@@ -0,0 +1,162 @@
+//! Just enough HTTP/1.1 for the admin API and the metrics exporter
+
+use crate::shutdown::Shutdown;
+use crate::utils::Backoff;
+use async_std::io;
+use async_std::net::TcpListener;
+use async_std::net::TcpStream;
+use async_std::stream::StreamExt;
+use async_std::task;
+use futures::AsyncReadExt;
+use futures::AsyncWriteExt;
+use futures::FutureExt;
+use log::warn;
+use std::future::Future;
+use std::time::Duration;
+
+/// Longest request head accepted
+const MAX_HEAD: usize = 8192;
+
+pub(crate) struct Request {
+    pub(crate) method: String,
+    /// Segments of the path, each one percent decoded
+    pub(crate) path: Vec<String>,
+    /// Of an `Authorization: Bearer` header
+    pub(crate) token: Option<String>,
+}
+
+pub(crate) struct Response {
+    pub(crate) status: u16,
+    pub(crate) content_type: &'static str,
+    pub(crate) body: String,
+}
+
+impl Response {
+    pub(crate) fn json(status: u16, body: serde_json::Value) -> Response {
+        Response {
+            status,
+            content_type: "application/json",
+            body: body.to_string(),
+        }
+    }
+}
+
+/// Answer each request with `route(request)` until `stop`
+pub(crate) async fn serve<F, R>(listener: TcpListener, name: &'static str, stop: Shutdown, route: F)
+where
+    F: Fn(Request) -> R + Clone + Send + 'static,
+    R: Future<Output = Response> + Send,
+{
+    let mut incoming = listener.incoming();
+    let mut backoff = Backoff::new();
+    loop {
+        let stream = futures::select! {
+            stream = incoming.next().fuse() => stream,
+            _ = stop.wait().fuse() => return,
+        };
+        let stream = match stream {
+            Some(Ok(stream)) => stream,
+            None => return,
+            Some(Err(e)) => {
+                warn!(target: "shadow-peer", "{} accept: {}", name, e);
+                backoff.wait().await;
+                continue;
+            }
+        };
+        backoff.reset();
+        let route = route.clone();
+        task::spawn(async move {
+            if let Err(e) = handle(stream, route).await {
+                warn!(target: "shadow-peer", "{} request: {}", name, e);
+            }
+        });
+    }
+}
+
+async fn handle<F, R>(mut stream: TcpStream, route: F) -> io::Result<()>
+where
+    F: Fn(Request) -> R,
+    R: Future<Output = Response>,
+{
+    let head = io::timeout(Duration::from_secs(10), read_head(&mut stream)).await?;
+    let resp = match parse(&head) {
+        Some(request) => route(request).await,
+        None => Response::json(400, serde_json::json!({ "error": "Bad request" })),
+    };
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        resp.status,
+        reason(resp.status),
+        resp.content_type,
+        resp.body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(resp.body.as_bytes()).await
+}
+
+async fn read_head(stream: &mut TcpStream) -> io::Result<String> {
+    let mut head = vec![];
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        if head.len() > MAX_HEAD {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Request too long",
+            ));
+        }
+        let len = stream.read(&mut buf).await?;
+        if len == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        head.extend_from_slice(&buf[..len]);
+    }
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+fn parse(head: &str) -> Option<Request> {
+    let mut lines = head.split("\r\n");
+    let mut line = lines.next()?.split_whitespace();
+    let (method, path) = (line.next()?, line.next()?);
+    // Split before decoding, an encoded `/` stays in its segment
+    let path = path.trim_matches('/').split('/').map(decode).collect();
+    let headers = lines.filter_map(|l| l.split_once(':'));
+    let mut auth = headers.filter(|(name, _)| name.trim().eq_ignore_ascii_case("authorization"));
+    let token = auth.find_map(|(_, value)| value.trim().strip_prefix("Bearer "));
+    Some(Request {
+        method: method.to_string(),
+        path,
+        token: token.map(|t| t.trim().to_string()),
+    })
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        _ => "Service Unavailable",
+    }
+}
+
+/// Undo percent encoding of a path segment
+fn decode(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3);
+        let hex = hex.and_then(|h| std::str::from_utf8(h).ok());
+        match (bytes[i], hex.and_then(|h| u8::from_str_radix(h, 16).ok())) {
+            (b'%', Some(b)) => {
+                out.push(b);
+                i += 3;
+            }
+            (b, _) => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
@@ -1,7 +1,21 @@
 #![recursion_limit = "256"]
 
 pub mod client;
+mod dial;
 mod error;
+mod heartbeat;
+mod http;
+mod metrics;
+mod mux;
 mod protocol;
+mod proxy;
+mod ratelimit;
+mod reload;
 pub mod server;
+mod shutdown;
+mod stream;
+mod timeouts;
+pub mod tls;
 mod utils;
+
+pub use self::error::Error;
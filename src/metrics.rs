@@ -0,0 +1,188 @@
+//! Counters and gauges, exported in the Prometheus text format
+
+use crate::http;
+use crate::http::Response;
+use crate::shutdown::Shutdown;
+use async_std::net::TcpListener;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+/// Name and help of a metric
+pub(crate) struct Desc {
+    name: &'static str,
+    help: &'static str,
+}
+
+pub(crate) const VISITOR_BYTES: Desc = Desc {
+    name: "shadow_peer_visitor_bytes_total",
+    help: "Bytes from (in) and to (out) visitors",
+};
+pub(crate) const LOCAL_BYTES: Desc = Desc {
+    name: "shadow_peer_local_bytes_total",
+    help: "Bytes to (in) and from (out) local services",
+};
+pub(crate) const SESSIONS: Desc = Desc {
+    name: "shadow_peer_sessions_total",
+    help: "Visitor sessions by result: opened, failed, timeout, rejected or denied",
+};
+pub(crate) const UNMATCHED_WORKERS: Desc = Desc {
+    name: "shadow_peer_unmatched_workers_total",
+    help: "Worker connections which came after their visitor was gone",
+};
+pub(crate) const PING_RTT: Desc = Desc {
+    name: "shadow_peer_ping_rtt_seconds",
+    help: "Round trip time of the last ping",
+};
+pub(crate) const PING_JITTER: Desc = Desc {
+    name: "shadow_peer_ping_jitter_seconds",
+    help: "Smoothed variation of the ping round trip time",
+};
+pub(crate) const CLIENTS: Desc = Desc {
+    name: "shadow_peer_clients",
+    help: "Clients connected",
+};
+pub(crate) const CONNECTED: Desc = Desc {
+    name: "shadow_peer_connected",
+    help: "Control connections up",
+};
+pub(crate) const RECONNECTS: Desc = Desc {
+    name: "shadow_peer_reconnects_total",
+    help: "Control connections lost",
+};
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Counter,
+    Gauge,
+}
+
+struct Family {
+    help: &'static str,
+    kind: Kind,
+    /// By rendered labels
+    values: BTreeMap<String, Arc<AtomicU64>>,
+}
+
+/// Metrics of a running server or client
+#[derive(Clone, Default)]
+pub(crate) struct Metrics {
+    families: Arc<Mutex<BTreeMap<&'static str, Family>>>,
+}
+
+#[derive(Clone)]
+pub(crate) struct Counter(Arc<AtomicU64>);
+
+/// Kept as the bits of an f64
+#[derive(Clone)]
+pub(crate) struct Gauge(Arc<AtomicU64>);
+
+impl Metrics {
+    pub(crate) fn counter(&self, desc: &Desc, labels: &[(&str, &str)]) -> Counter {
+        Counter(self.value(desc, Kind::Counter, labels))
+    }
+
+    pub(crate) fn gauge(&self, desc: &Desc, labels: &[(&str, &str)]) -> Gauge {
+        Gauge(self.value(desc, Kind::Gauge, labels))
+    }
+
+    /// Stop exporting `desc` with `labels`, once what it measures is gone
+    pub(crate) fn remove(&self, desc: &Desc, labels: &[(&str, &str)]) {
+        let mut families = self.families.lock().unwrap();
+        if let Some(family) = families.get_mut(desc.name) {
+            family.values.remove(&render_labels(labels));
+        }
+    }
+
+    fn value(&self, desc: &Desc, kind: Kind, labels: &[(&str, &str)]) -> Arc<AtomicU64> {
+        let mut families = self.families.lock().unwrap();
+        let family = families.entry(desc.name).or_insert_with(|| Family {
+            help: desc.help,
+            kind,
+            values: BTreeMap::new(),
+        });
+        let labels = render_labels(labels);
+        family.values.entry(labels).or_default().clone()
+    }
+
+    fn render(&self) -> String {
+        let families = self.families.lock().unwrap();
+        let mut out = String::new();
+        for (name, family) in families.iter() {
+            let kind = match family.kind {
+                Kind::Counter => "counter",
+                Kind::Gauge => "gauge",
+            };
+            let _ = writeln!(out, "# HELP {} {}", name, family.help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            for (labels, value) in &family.values {
+                let value = value.load(Ordering::Relaxed);
+                let value = match family.kind {
+                    Kind::Counter => value.to_string(),
+                    Kind::Gauge => f64::from_bits(value).to_string(),
+                };
+                let _ = match labels.is_empty() {
+                    true => writeln!(out, "{} {}", name, value),
+                    false => writeln!(out, "{}{{{}}} {}", name, labels, value),
+                };
+            }
+        }
+        out
+    }
+}
+
+impl Counter {
+    pub(crate) fn inc(&self) {
+        self.add(1);
+    }
+
+    pub(crate) fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+}
+
+impl Gauge {
+    pub(crate) fn set(&self, v: f64) {
+        self.0.store(v.to_bits(), Ordering::Relaxed);
+    }
+
+    pub(crate) fn add(&self, v: f64) {
+        let add = |bits| Some((f64::from_bits(bits) + v).to_bits());
+        let _ = self
+            .0
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, add);
+    }
+}
+
+/// Serve `metrics` on GET /metrics until `stop`
+pub(crate) async fn serve(listener: TcpListener, metrics: Metrics, stop: Shutdown) {
+    http::serve(listener, "Metrics", stop, move |req| {
+        let resp = match (req.method.as_str(), req.path.as_slice()) {
+            ("GET", [path]) if path == "metrics" => Response {
+                status: 200,
+                content_type: "text/plain; version=0.0.4",
+                body: metrics.render(),
+            },
+            _ => Response::json(404, serde_json::json!({ "error": "Not found" })),
+        };
+        async move { resp }
+    })
+    .await
+}
+
+fn render_labels(labels: &[(&str, &str)]) -> String {
+    labels
+        .iter()
+        .map(|(k, v)| format!("{}=\"{}\"", k, escape(v)))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn escape(v: &str) -> String {
+    v.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,367 @@
+//! Multiplexed streams over the control connection
+//!
+//! The server opens a stream with `Open(id, Establish)`, both sides then send
+//! `Data` frames until `Fin`, or abort with `Rst`. A side may only send as
+//! many bytes as the peer granted, `Window` frames return credit once the
+//! data is consumed. A stream whose peer sends more than granted is reset.
+
+use crate::protocol::Protocol;
+use crate::protocol::StreamId;
+use futures::channel::mpsc;
+use futures::channel::mpsc::UnboundedReceiver;
+use futures::channel::mpsc::UnboundedSender;
+use futures::io::AsyncRead;
+use futures::io::AsyncWrite;
+use futures::StreamExt;
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+use std::pin::Pin;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::task::Context;
+use std::task::Poll;
+use std::task::Waker;
+
+/// Initial credit of each direction of a stream
+pub const WINDOW: u32 = 256 * 1024;
+const MAX_FRAME: usize = 16 * 1024;
+
+pub struct Session {
+    sender: UnboundedSender<Protocol>,
+    streams: Mutex<HashMap<StreamId, Entry>>,
+    next_id: AtomicU32,
+}
+
+struct Entry {
+    data: Option<UnboundedSender<Vec<u8>>>,
+    /// Bytes the peer may still send
+    credit: u32,
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
+    window: u32,
+    reset: bool,
+    waker: Option<Waker>,
+}
+
+impl State {
+    fn wake(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn reset(&mut self) {
+        self.reset = true;
+        self.wake();
+    }
+}
+
+impl Session {
+    /// `sender` queues frames for the control connection
+    pub fn new(sender: UnboundedSender<Protocol>) -> Arc<Session> {
+        Arc::new(Session {
+            sender,
+            streams: Mutex::new(HashMap::new()),
+            next_id: AtomicU32::new(1),
+        })
+    }
+
+    /// Open a new stream, the caller announces it with `Protocol::Open`.
+    pub fn open(self: &Arc<Self>) -> (StreamId, MuxStream) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        (id, self.accept(id))
+    }
+
+    /// Register a stream opened by the peer
+    pub fn accept(self: &Arc<Self>, id: StreamId) -> MuxStream {
+        let (send, recv) = mpsc::unbounded();
+        let state = Arc::new(Mutex::new(State {
+            window: WINDOW,
+            reset: false,
+            waker: None,
+        }));
+        let entry = Entry {
+            data: Some(send),
+            credit: WINDOW,
+            state: state.clone(),
+        };
+        self.streams.lock().unwrap().insert(id, entry);
+        MuxStream {
+            id,
+            session: self.clone(),
+            state,
+            recv,
+            buf: vec![],
+            pos: 0,
+            consumed: 0,
+            fin: false,
+        }
+    }
+
+    /// Reject a stream opened by the peer
+    pub fn reject(&self, id: StreamId) {
+        let _ = self.sender.unbounded_send(Protocol::Rst(id));
+    }
+
+    /// Handle a stream frame, give back any other protocol.
+    pub fn dispatch(&self, proto: Protocol) -> Option<Protocol> {
+        let mut streams = self.streams.lock().unwrap();
+        match proto {
+            Protocol::Data(id, data) => match streams.get_mut(&id) {
+                Some(entry) if data.len() > entry.credit as usize => {
+                    if let Some(entry) = streams.remove(&id) {
+                        entry.state.lock().unwrap().reset();
+                    }
+                    let _ = self.sender.unbounded_send(Protocol::Rst(id));
+                }
+                Some(entry) => {
+                    entry.credit -= data.len() as u32;
+                    if let Some(s) = &entry.data {
+                        let _ = s.unbounded_send(data);
+                    }
+                }
+                None => {}
+            },
+            Protocol::Window(id, credit) => {
+                if let Some(entry) = streams.get(&id) {
+                    let mut state = entry.state.lock().unwrap();
+                    state.window = state.window.saturating_add(credit);
+                    state.wake();
+                }
+            }
+            Protocol::Fin(id) => {
+                if let Some(entry) = streams.get_mut(&id) {
+                    entry.data = None;
+                }
+            }
+            Protocol::Rst(id) => {
+                if let Some(entry) = streams.remove(&id) {
+                    entry.state.lock().unwrap().reset();
+                }
+            }
+            proto => return Some(proto),
+        }
+        None
+    }
+
+    /// Reset all streams, when the control connection is gone.
+    pub fn close(&self) {
+        for (_, entry) in self.streams.lock().unwrap().drain() {
+            entry.state.lock().unwrap().reset();
+        }
+    }
+
+    fn send(&self, proto: Protocol) -> io::Result<()> {
+        self.sender
+            .unbounded_send(proto)
+            .map_err(|_| io::ErrorKind::BrokenPipe.into())
+    }
+}
+
+pub struct MuxStream {
+    id: StreamId,
+    session: Arc<Session>,
+    state: Arc<Mutex<State>>,
+    recv: UnboundedReceiver<Vec<u8>>,
+    buf: Vec<u8>,
+    pos: usize,
+    consumed: u32,
+    fin: bool,
+}
+
+impl AsyncRead for MuxStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.pos == self.buf.len() {
+            match self.recv.poll_next_unpin(cx) {
+                Poll::Ready(Some(data)) => {
+                    self.buf = data;
+                    self.pos = 0;
+                }
+                Poll::Ready(None) if self.state.lock().unwrap().reset => {
+                    return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+                }
+                Poll::Ready(None) => return Poll::Ready(Ok(0)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        let len = buf.len().min(self.buf.len() - self.pos);
+        buf[..len].copy_from_slice(&self.buf[self.pos..self.pos + len]);
+        self.pos += len;
+        self.consumed += len as u32;
+        if self.consumed >= WINDOW / 2 {
+            let consumed = mem::take(&mut self.consumed);
+            if let Some(entry) = self.session.streams.lock().unwrap().get_mut(&self.id) {
+                entry.credit += consumed;
+            }
+            self.session.send(Protocol::Window(self.id, consumed))?;
+        }
+        Poll::Ready(Ok(len))
+    }
+}
+
+impl AsyncWrite for MuxStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let len = {
+            let mut state = self.state.lock().unwrap();
+            if state.reset {
+                return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+            }
+            if state.window == 0 {
+                state.waker = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+            let len = buf.len().min(state.window as usize).min(MAX_FRAME);
+            state.window -= len as u32;
+            len
+        };
+        let data = Protocol::Data(self.id, buf[..len].to_vec());
+        Poll::Ready(self.session.send(data).map(|_| len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if !self.fin {
+            self.fin = true;
+            self.session.send(Protocol::Fin(self.id))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for MuxStream {
+    fn drop(&mut self) {
+        let entry = self.session.streams.lock().unwrap().remove(&self.id);
+        let reset = self.state.lock().unwrap().reset;
+        if entry.is_some() && !reset && !self.fin {
+            let _ = self.session.send(Protocol::Fin(self.id));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::AsyncReadExt;
+    use futures::AsyncWriteExt;
+    use futures::FutureExt;
+
+    fn session() -> (Arc<Session>, UnboundedReceiver<Protocol>) {
+        let (send, recv) = mpsc::unbounded();
+        (Session::new(send), recv)
+    }
+
+    /// Frames sent by the session so far
+    fn sent(recv: &mut UnboundedReceiver<Protocol>) -> Vec<Protocol> {
+        let mut sent = vec![];
+        while let Ok(Some(proto)) = recv.try_next() {
+            sent.push(proto);
+        }
+        sent
+    }
+
+    #[test]
+    fn write_within_window() {
+        let (session, mut recv) = session();
+        let (id, mut stream) = session.open();
+        let data = vec![0u8; WINDOW as usize + 1];
+        let written = stream.write(&data).now_or_never().unwrap().unwrap();
+        assert_eq!(written, MAX_FRAME);
+        let mut total = written;
+        while total < WINDOW as usize {
+            total += stream
+                .write(&data[total..])
+                .now_or_never()
+                .unwrap()
+                .unwrap();
+        }
+        assert_eq!(total, WINDOW as usize);
+        assert!(stream.write(&data[total..]).now_or_never().is_none());
+        let frames = sent(&mut recv);
+        assert!(frames
+            .iter()
+            .all(|f| matches!(f, Protocol::Data(i, _) if *i == id)));
+
+        assert!(session.dispatch(Protocol::Window(id, 1)).is_none());
+        assert_eq!(
+            stream
+                .write(&data[total..])
+                .now_or_never()
+                .unwrap()
+                .unwrap(),
+            1
+        );
+    }
+
+    #[test]
+    fn read_returns_credit() {
+        let (session, mut recv) = session();
+        let mut stream = session.accept(1);
+        let half = WINDOW as usize / 2;
+        assert!(session.dispatch(Protocol::Data(1, vec![7; half])).is_none());
+        let mut buf = vec![0u8; half];
+        stream.read_exact(&mut buf).now_or_never().unwrap().unwrap();
+        assert!(buf.iter().all(|b| *b == 7));
+        match sent(&mut recv).as_slice() {
+            [Protocol::Window(1, credit)] => assert_eq!(*credit as usize, half),
+            frames => panic!("{:?}", frames),
+        }
+        // The credit given back lets the peer send a full window again
+        assert!(session.dispatch(Protocol::Data(1, vec![0; half])).is_none());
+        assert!(session.dispatch(Protocol::Data(1, vec![0; half])).is_none());
+        assert!(sent(&mut recv).is_empty());
+    }
+
+    #[test]
+    fn data_over_credit_resets() {
+        let (session, mut recv) = session();
+        let mut stream = session.accept(1);
+        let data = vec![0u8; WINDOW as usize + 1];
+        assert!(session.dispatch(Protocol::Data(1, data)).is_none());
+        assert!(matches!(sent(&mut recv).as_slice(), [Protocol::Rst(1)]));
+        let mut buf = [0u8; 16];
+        let e = stream.read(&mut buf).now_or_never().unwrap().unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionReset);
+        let e = stream.write(&buf).now_or_never().unwrap().unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionReset);
+    }
+
+    #[test]
+    fn fin_ends_reads() {
+        let (session, _recv) = session();
+        let mut stream = session.accept(1);
+        assert!(session
+            .dispatch(Protocol::Data(1, b"abc".to_vec()))
+            .is_none());
+        assert!(session.dispatch(Protocol::Fin(1)).is_none());
+        let mut read = vec![];
+        stream
+            .read_to_end(&mut read)
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        assert_eq!(read, b"abc");
+    }
+
+    #[test]
+    fn other_frames_given_back() {
+        let (session, _recv) = session();
+        let r = session.dispatch(Protocol::Ping(1));
+        assert!(matches!(r, Some(Protocol::Ping(1))));
+    }
+}
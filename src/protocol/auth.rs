@@ -0,0 +1,75 @@
+//! Challenge / response handshake for the control connection
+//!  client                              server
+//!    |------ ClientId(id) --------------->|
+//!    |<----- Challenge(nonce) ------------|
+//!    |------ Response(HMAC-SHA256) ------>|
+//! HMAC = HMAC-SHA256(secret, id || nonce)
+//!
+//! Each `Establish` sent over the authenticated control connection carries a
+//! one-time `Token`, the worker connection must echo it back to be paired.
+
+use super::ClientId;
+use hmac::Hmac;
+use hmac::Mac;
+use hmac::NewMac;
+use rand::RngCore;
+use sha2::Sha256;
+
+pub const NONCE_LEN: usize = 32;
+
+pub type Token = u64;
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub fn nonce() -> Vec<u8> {
+    let mut nonce = vec![0u8; NONCE_LEN];
+    rand::thread_rng().fill_bytes(nonce.as_mut_slice());
+    nonce
+}
+
+pub fn token() -> Token {
+    rand::thread_rng().next_u64()
+}
+
+pub fn sign(secret: &str, id: &ClientId, nonce: &[u8]) -> Vec<u8> {
+    mac(secret, id, nonce).finalize().into_bytes().to_vec()
+}
+
+pub fn verify(secret: &str, id: &ClientId, nonce: &[u8], sign: &[u8]) -> bool {
+    mac(secret, id, nonce).verify(sign).is_ok()
+}
+
+fn mac(secret: &str, id: &ClientId, nonce: &[u8]) -> HmacSha256 {
+    // HMAC accepts keys of any length
+    let mut mac = HmacSha256::new_varkey(secret.as_bytes()).expect("HMAC key");
+    mac.update(id.as_bytes());
+    mac.update(nonce);
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sign_verify() {
+        let id = "c1".to_string();
+        let nonce = nonce();
+        let sign = sign("s3cret", &id, &nonce);
+        assert_eq!(sign.len(), 32);
+        assert!(verify("s3cret", &id, &nonce, &sign));
+        assert!(!verify("other", &id, &nonce, &sign));
+        assert!(!verify("s3cret", &"c2".to_string(), &nonce, &sign));
+        assert!(!verify("s3cret", &id, &nonce, &sign[..31]));
+    }
+
+    #[test]
+    fn replayed_response() {
+        let id = "c1".to_string();
+        let (first, second) = (nonce(), nonce());
+        assert_eq!(first.len(), NONCE_LEN);
+        assert_ne!(first, second);
+        let sign = sign("s3cret", &id, &first);
+        assert!(!verify("s3cret", &id, &second, &sign));
+    }
+}
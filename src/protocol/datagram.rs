@@ -0,0 +1,100 @@
+//! Datagram framing over a worker connection, In BigEndian
+//!  0                 15
+//! --------------------
+//! |len 16bit         |
+//! --------------------
+//! payload......(len)
+
+use super::Read;
+use super::Write;
+use crate::error::Error;
+use crate::error::Result;
+use futures::Stream;
+
+pub async fn write_datagram<W>(writer: &mut W, data: &[u8]) -> Result<()>
+where
+    W: Write + Unpin,
+{
+    if data.len() > u16::MAX as usize {
+        Err(Error::InvalidOperation(format!(
+            "Datagram too long: {}",
+            data.len()
+        )))?;
+    }
+    let mut buf = Vec::with_capacity(data.len() + 2);
+    buf.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    buf.extend_from_slice(data);
+    writer.write_all(buf.as_slice()).await?;
+    Ok(())
+}
+
+pub async fn read_datagram<R>(reader: &mut R) -> Result<Vec<u8>>
+where
+    R: Read + Unpin,
+{
+    let mut len = [0u8; 2];
+    reader.read_exact(&mut len).await?;
+    let mut buf = vec![0u8; u16::from_be_bytes(len) as usize];
+    reader.read_exact(buf.as_mut_slice()).await?;
+    Ok(buf)
+}
+
+/// Datagrams read from `reader`, a pending read is kept across polls.
+pub fn datagram_stream<R>(reader: R) -> impl Stream<Item = Result<Vec<u8>>>
+where
+    R: Read + Unpin,
+{
+    futures::stream::unfold(reader, |mut reader| async move {
+        let data = read_datagram(&mut reader).await;
+        Some((data, reader))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::io::Cursor;
+    use async_std::task;
+    use futures::StreamExt;
+
+    #[test]
+    fn round_trip() {
+        let mut buf = vec![];
+        task::block_on(async {
+            write_datagram(&mut buf, b"hello").await.unwrap();
+            write_datagram(&mut buf, b"").await.unwrap();
+            write_datagram(&mut buf, &[1; 300]).await.unwrap();
+        });
+        assert_eq!(&buf[..7], b"\x00\x05hello");
+        let mut datagrams = Box::pin(datagram_stream(Cursor::new(buf)));
+        task::block_on(async {
+            assert_eq!(datagrams.next().await.unwrap().unwrap(), b"hello");
+            assert_eq!(datagrams.next().await.unwrap().unwrap(), b"");
+            assert_eq!(datagrams.next().await.unwrap().unwrap(), vec![1; 300]);
+            assert!(datagrams.next().await.unwrap().is_err());
+        });
+    }
+
+    #[test]
+    fn largest() {
+        let mut buf = vec![];
+        let data = vec![2; u16::MAX as usize];
+        task::block_on(write_datagram(&mut buf, &data)).unwrap();
+        let read = task::block_on(read_datagram(&mut Cursor::new(buf))).unwrap();
+        assert_eq!(read, data);
+    }
+
+    #[test]
+    fn too_long() {
+        let mut buf = vec![];
+        let r = task::block_on(write_datagram(&mut buf, &vec![0; u16::MAX as usize + 1]));
+        assert!(matches!(r, Err(Error::InvalidOperation(_))));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn truncated() {
+        let r = task::block_on(read_datagram(&mut Cursor::new(b"\x00\x05hel".to_vec())));
+        assert!(matches!(r, Err(Error::Io(_))));
+    }
+}
@@ -1,36 +1,138 @@
+use self::auth::Token;
 use self::net_proto::Establish;
+use self::net_proto::Remote;
 use crate::error::Error;
 use crate::error::Result;
 use async_std::future::timeout;
 use async_std::io::prelude::WriteExt as Write;
 use async_std::io::ReadExt as Read;
+use futures::Stream;
 use serde::Deserialize;
 use serde::Serialize;
+use std::net::SocketAddr;
+use std::result::Result as StdResult;
 use std::time::Duration;
 
+pub mod auth;
+pub mod datagram;
 pub mod net_proto;
 pub mod v0;
+pub mod v1;
 
 pub type ClientId = String;
+pub type StreamId = u32;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Protocol {
     ClientId(String),
-    Establish(Establish),
+    Establish(Establish, Token),
+    /// Echoed as is, by peers without `caps::HEARTBEAT`
     Ping(u16),
+    Challenge(Vec<u8>),
+    Response(Vec<u8>),
+    Hello(Hello),
+    Open(StreamId, Establish),
+    Data(StreamId, Vec<u8>),
+    Window(StreamId, u32),
+    Fin(StreamId),
+    Rst(StreamId),
+    Pool(Token),
+    Park(ClientId, Token),
+    Register(Vec<Remote>),
+    /// The address bound for each `Register` entry, or why it was refused
+    Registered(Vec<StdResult<SocketAddr, String>>),
+    /// The peer is shutting down, no new stream will be accepted
+    Goodbye,
+    /// Sequence number and the sender's monotonic milliseconds
+    Heartbeat(u32, u64),
+    /// Answers `Heartbeat` with its fields
+    Pong(u32, u64),
 }
 
-pub const CURRENT_VERSION: u8 = 0;
+/// Sent by the client before `ClientId`, the server answers with the
+/// capabilities both sides support and the version chosen for the rest of
+/// the control connection. Clients without `Hello` get none, and `v0`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Hello {
+    pub caps: u32,
+    #[serde(default)]
+    pub versions: Vec<u8>,
+    /// Most worker connections the server parks per login, 0 if unknown
+    #[serde(default)]
+    pub max_pool: u32,
+}
+
+pub mod caps {
+    /// Carry all visitor streams over the control connection
+    pub const MULTIPLEX: u32 = 1;
+    /// Park idle worker connections at the server
+    pub const POOL: u32 = 2;
+    /// Open listeners requested by the client, right after login
+    pub const REGISTER: u32 = 4;
+    /// Ping with `Heartbeat`, both ways, and answer with `Pong`
+    pub const HEARTBEAT: u32 = 8;
+
+    pub const SUPPORTED: u32 = MULTIPLEX | POOL | REGISTER | HEARTBEAT;
+}
+
+/// Longest a parked worker connection is left idle by the client, it is
+/// parked again afterwards
+pub const PARK_IDLE: Duration = Duration::from_secs(60);
+
+/// Longest frame body read before the peer is authenticated
+pub const LOGIN_MAX: usize = 4096;
 
-pub async fn read_protocol_timeout<R>(reader: &mut R, tmout: u64) -> Result<Protocol>
+/// Version of `Hello`, and of any connection without one
+pub const BASE_VERSION: u8 = 0;
+/// Versions this side could speak
+pub const VERSIONS: &[u8] = &[0, 1];
+
+/// The newest version in `versions` also supported here
+pub fn negotiate(versions: &[u8]) -> u8 {
+    versions
+        .iter()
+        .filter(|v| VERSIONS.contains(v))
+        .max()
+        .copied()
+        .unwrap_or(BASE_VERSION)
+}
+
+pub async fn read_protocol_timeout<R>(reader: &mut R, tmout: Duration) -> Result<Protocol>
 where
     R: Read + Unpin,
 {
-    let dur = Duration::from_secs(tmout);
-    timeout(dur, async { read_protocol(reader).await }).await?
+    timeout(tmout, async { read_protocol(reader).await }).await?
+}
+
+/// As `read_protocol_timeout`, from a peer not authenticated yet, whose
+/// frames are no longer than `LOGIN_MAX`
+pub async fn read_login_timeout<R>(reader: &mut R, tmout: Duration) -> Result<Protocol>
+where
+    R: Read + Unpin,
+{
+    timeout(tmout, async { read_frame(reader, LOGIN_MAX).await }).await?
+}
+
+/// Protocols read from `reader`, a pending read is kept across polls.
+pub fn protocol_stream<R>(reader: R, tmout: Duration) -> impl Stream<Item = Result<Protocol>>
+where
+    R: Read + Unpin,
+{
+    futures::stream::unfold(reader, move |mut reader| async move {
+        let proto = read_protocol_timeout(&mut reader, tmout).await;
+        Some((proto, reader))
+    })
 }
 
 pub async fn read_protocol<R>(reader: &mut R) -> Result<Protocol>
+where
+    R: Read + Unpin,
+{
+    read_frame(reader, usize::MAX).await
+}
+
+/// Read a frame of any version, whose body is no longer than `max`
+async fn read_frame<R>(reader: &mut R, max: usize) -> Result<Protocol>
 where
     R: Read + Unpin,
 {
@@ -38,7 +140,8 @@ where
     reader.read_exact(&mut header).await?;
     let version = header[0];
     let r = match version {
-        0 => v0::parse(reader, header).await?,
+        0 => v0::parse(reader, header, max).await?,
+        1 => v1::parse(reader, header, max).await?,
         ver => Err(Error::UnsupportedVersion(ver))?,
     };
     Ok(r)
@@ -50,6 +153,7 @@ where
 {
     let buf = match version {
         0 => v0::build_protocol(proto),
+        1 => v1::build_protocol(proto),
         ver => Err(Error::UnsupportedVersion(ver))?,
     }?;
     writer.write_all(buf.as_slice()).await?;
@@ -1,10 +1,52 @@
 use serde::Deserialize;
 use serde::Serialize;
+use std::fmt;
+use std::net::IpAddr;
+use std::net::Ipv6Addr;
 use std::net::SocketAddr;
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Proto {
+    Tcp,
+    Udp,
+}
+
+impl fmt::Display for Proto {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Proto::Tcp => write!(f, "tcp"),
+            Proto::Udp => write!(f, "udp"),
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum Establish {
     Tcp(TcpEstablish),
+    Udp(UdpEstablish),
+}
+
+impl Establish {
+    pub fn proto(&self) -> Proto {
+        match self {
+            Establish::Tcp(_) => Proto::Tcp,
+            Establish::Udp(_) => Proto::Udp,
+        }
+    }
+
+    pub fn src(&self) -> SocketAddr {
+        match self {
+            Establish::Tcp(est) => est.src,
+            Establish::Udp(est) => est.src,
+        }
+    }
+
+    pub fn dest(&self) -> SocketAddr {
+        match self {
+            Establish::Tcp(est) => est.dest,
+            Establish::Udp(est) => est.dest,
+        }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
@@ -13,7 +55,35 @@ pub struct TcpEstablish {
     pub dest: SocketAddr,
 }
 
-#[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+/// One UDP session, identified by the visitor's source address
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct UdpEstablish {
+    pub src: SocketAddr,
+    pub dest: SocketAddr,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum Listen {
     Tcp(SocketAddr),
+    Udp(SocketAddr),
+}
+
+/// A listener the client asks the server to open for it
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Remote {
+    pub proto: Proto,
+    pub port: u16,
+    /// Listen on all addresses if not given
+    pub bind: Option<IpAddr>,
+}
+
+impl Remote {
+    pub fn listen(&self) -> Listen {
+        let ip = self.bind.unwrap_or(IpAddr::V6(Ipv6Addr::UNSPECIFIED));
+        let socket = SocketAddr::new(ip, self.port);
+        match self.proto {
+            Proto::Tcp => Listen::Tcp(socket),
+            Proto::Udp => Listen::Udp(socket),
+        }
+    }
 }
@@ -4,7 +4,12 @@
 //! |ver 8bit|opcode 8bit|len / param 16bit     |
 //! ---------------------------------------------
 //! Json / other, depend on opcode......(len)
-//! opcode: 0 -> parse Json
+//! opcode: 0 -> Ping, param is the timestamp
+//!         1 -> parse Json
+//!         2 -> Data, stream id 32bit + payload(len)
+//!         3 -> Window, stream id 32bit + credit 32bit
+//!         4 -> Fin, stream id 32bit
+//!         5 -> Rst, stream id 32bit
 
 use super::Read;
 use crate::error::Error;
@@ -14,14 +19,20 @@ use byteorder::ReadBytesExt;
 use byteorder::WriteBytesExt;
 
 use super::Protocol;
+use super::StreamId;
 use std::io::Cursor;
 
 mod opcode {
     pub const PING: u8 = 0;
     pub const JSON: u8 = 1;
+    pub const DATA: u8 = 2;
+    pub const WINDOW: u8 = 3;
+    pub const FIN: u8 = 4;
+    pub const RST: u8 = 5;
 }
 
-pub async fn parse<R>(reader: &mut R, header: [u8; 4]) -> Result<Protocol>
+/// Parse the frame after `header`, whose body is no longer than `max`
+pub async fn parse<R>(reader: &mut R, header: [u8; 4], max: usize) -> Result<Protocol>
 where
     R: Read + Unpin,
 {
@@ -29,9 +40,21 @@ where
     header.read_u8()?; // drop version
     let op = header.read_u8()?;
     let len = header.read_u16::<BigEndian>()?;
+    if (op == opcode::JSON || op == opcode::DATA) && len as usize > max {
+        Err(Error::InvalidOperation(format!("Frame too long: {}", len)))?;
+    }
     let protocol = match op {
         opcode::PING => Protocol::Ping(len),
         opcode::JSON => parse_json(reader, len as usize).await?,
+        opcode::DATA => {
+            let id = read_u32(reader).await?;
+            let mut data = vec![0u8; len as usize];
+            reader.read_exact(data.as_mut_slice()).await?;
+            Protocol::Data(id, data)
+        }
+        opcode::WINDOW => Protocol::Window(read_u32(reader).await?, read_u32(reader).await?),
+        opcode::FIN => Protocol::Fin(read_u32(reader).await?),
+        opcode::RST => Protocol::Rst(read_u32(reader).await?),
         _ => Err(Error::InvalidOperation(format!("invalid opcode {}", op)))?,
     };
     Ok(protocol)
@@ -42,6 +65,24 @@ pub fn build_protocol(proto: &Protocol) -> Result<Vec<u8>> {
     buf.write_u8(0)?;
     let (op, param, append) = match proto {
         Protocol::Ping(ts) => (opcode::PING, *ts, None),
+        Protocol::Data(id, data) => {
+            if data.len() > u16::MAX as usize {
+                Err(Error::InvalidOperation(format!(
+                    "Data too long: {}",
+                    data.len()
+                )))?;
+            }
+            let mut append = stream_id(*id);
+            append.extend_from_slice(data);
+            (opcode::DATA, data.len() as u16, Some(append))
+        }
+        Protocol::Window(id, credit) => {
+            let mut append = stream_id(*id);
+            append.extend_from_slice(&credit.to_be_bytes());
+            (opcode::WINDOW, 0, Some(append))
+        }
+        Protocol::Fin(id) => (opcode::FIN, 0, Some(stream_id(*id))),
+        Protocol::Rst(id) => (opcode::RST, 0, Some(stream_id(*id))),
         _ => {
             let json = serde_json::to_vec(proto)?;
             let len = json.len();
@@ -52,7 +93,7 @@ pub fn build_protocol(proto: &Protocol) -> Result<Vec<u8>> {
         }
     };
     buf.write_u8(op)?;
-    buf.write_u16::<BigEndian>(param as u16)?;
+    buf.write_u16::<BigEndian>(param)?;
     if let Some(mut append) = append {
         buf.append(&mut append);
     }
@@ -68,3 +109,16 @@ where
     let r = serde_json::from_slice(buf.as_slice())?;
     Ok(r)
 }
+
+async fn read_u32<R>(reader: &mut R) -> Result<u32>
+where
+    R: Read + Unpin,
+{
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf).await?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn stream_id(id: StreamId) -> Vec<u8> {
+    id.to_be_bytes().to_vec()
+}
@@ -0,0 +1,327 @@
+//! Frame defination, In BigEndian
+//!  0                                          31
+//! ---------------------------------------------
+//! |ver 8bit|opcode 8bit|param 16bit           |
+//! ---------------------------------------------
+//! |len 32bit                                  |
+//! ---------------------------------------------
+//! Json / other, depend on opcode......(len)
+//! opcode: 0 -> Ping, param is the timestamp
+//!         1 -> parse Json
+//!         2 -> Data, stream id 32bit + payload
+//!         3 -> Window, stream id 32bit + credit 32bit
+//!         4 -> Fin, stream id 32bit
+//!         5 -> Rst, stream id 32bit
+//!         6 -> Establish, token 64bit + establish
+//!         7 -> Open, stream id 32bit + establish
+//!         8 -> Heartbeat, seq 32bit + timestamp 64bit
+//!         9 -> Pong, seq 32bit + timestamp 64bit
+//! establish: kind 8bit (0 -> Tcp, 1 -> Udp) + src addr + dest addr
+//! addr: family 8bit (4 / 6) + ip (4 / 16 bytes) + port 16bit
+
+use super::net_proto::Establish;
+use super::net_proto::TcpEstablish;
+use super::net_proto::UdpEstablish;
+use super::Protocol;
+use super::Read;
+use crate::error::Error;
+use crate::error::Result;
+use byteorder::BigEndian;
+use byteorder::ReadBytesExt;
+use byteorder::WriteBytesExt;
+use std::io::Cursor;
+use std::io::Read as _;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::net::SocketAddr;
+
+/// Largest frame body accepted
+const MAX_LEN: u32 = 16 * 1024 * 1024;
+/// A body is read this much at a time, the memory held grows with the bytes
+/// actually received rather than the length announced
+const CHUNK: usize = 64 * 1024;
+
+mod opcode {
+    pub const PING: u8 = 0;
+    pub const JSON: u8 = 1;
+    pub const DATA: u8 = 2;
+    pub const WINDOW: u8 = 3;
+    pub const FIN: u8 = 4;
+    pub const RST: u8 = 5;
+    pub const ESTABLISH: u8 = 6;
+    pub const OPEN: u8 = 7;
+    pub const HEARTBEAT: u8 = 8;
+    pub const PONG: u8 = 9;
+}
+
+/// Parse the frame after `header`, whose body is no longer than `max`
+pub async fn parse<R>(reader: &mut R, header: [u8; 4], max: usize) -> Result<Protocol>
+where
+    R: Read + Unpin,
+{
+    let mut header = Cursor::new(header);
+    header.read_u8()?; // drop version
+    let op = header.read_u8()?;
+    let param = header.read_u16::<BigEndian>()?;
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len).await?;
+    let len = u32::from_be_bytes(len);
+    if len > MAX_LEN || len as usize > max {
+        Err(Error::InvalidOperation(format!("Frame too long: {}", len)))?;
+    }
+    let mut body = vec![];
+    while body.len() < len as usize {
+        let at = body.len();
+        body.resize(at + CHUNK.min(len as usize - at), 0);
+        reader.read_exact(&mut body[at..]).await?;
+    }
+    if op == opcode::JSON {
+        return Ok(serde_json::from_slice(body.as_slice())?);
+    }
+
+    let mut body = Cursor::new(body);
+    let protocol = match op {
+        opcode::PING => Protocol::Ping(param),
+        opcode::DATA => {
+            let id = body.read_u32::<BigEndian>()?;
+            let mut data = vec![];
+            body.read_to_end(&mut data)?;
+            Protocol::Data(id, data)
+        }
+        opcode::WINDOW => {
+            let id = body.read_u32::<BigEndian>()?;
+            Protocol::Window(id, body.read_u32::<BigEndian>()?)
+        }
+        opcode::FIN => Protocol::Fin(body.read_u32::<BigEndian>()?),
+        opcode::RST => Protocol::Rst(body.read_u32::<BigEndian>()?),
+        opcode::ESTABLISH => {
+            let token = body.read_u64::<BigEndian>()?;
+            Protocol::Establish(read_establish(&mut body)?, token)
+        }
+        opcode::OPEN => {
+            let id = body.read_u32::<BigEndian>()?;
+            Protocol::Open(id, read_establish(&mut body)?)
+        }
+        opcode::HEARTBEAT => {
+            let seq = body.read_u32::<BigEndian>()?;
+            Protocol::Heartbeat(seq, body.read_u64::<BigEndian>()?)
+        }
+        opcode::PONG => {
+            let seq = body.read_u32::<BigEndian>()?;
+            Protocol::Pong(seq, body.read_u64::<BigEndian>()?)
+        }
+        _ => Err(Error::InvalidOperation(format!("invalid opcode {}", op)))?,
+    };
+    Ok(protocol)
+}
+
+pub fn build_protocol(proto: &Protocol) -> Result<Vec<u8>> {
+    let mut body = vec![];
+    let (op, param) = match proto {
+        Protocol::Ping(ts) => (opcode::PING, *ts),
+        Protocol::Data(id, data) => {
+            body.write_u32::<BigEndian>(*id)?;
+            body.extend_from_slice(data);
+            (opcode::DATA, 0)
+        }
+        Protocol::Window(id, credit) => {
+            body.write_u32::<BigEndian>(*id)?;
+            body.write_u32::<BigEndian>(*credit)?;
+            (opcode::WINDOW, 0)
+        }
+        Protocol::Fin(id) => {
+            body.write_u32::<BigEndian>(*id)?;
+            (opcode::FIN, 0)
+        }
+        Protocol::Rst(id) => {
+            body.write_u32::<BigEndian>(*id)?;
+            (opcode::RST, 0)
+        }
+        Protocol::Establish(est, token) => {
+            body.write_u64::<BigEndian>(*token)?;
+            write_establish(&mut body, est)?;
+            (opcode::ESTABLISH, 0)
+        }
+        Protocol::Open(id, est) => {
+            body.write_u32::<BigEndian>(*id)?;
+            write_establish(&mut body, est)?;
+            (opcode::OPEN, 0)
+        }
+        Protocol::Heartbeat(seq, ts) => {
+            body.write_u32::<BigEndian>(*seq)?;
+            body.write_u64::<BigEndian>(*ts)?;
+            (opcode::HEARTBEAT, 0)
+        }
+        Protocol::Pong(seq, ts) => {
+            body.write_u32::<BigEndian>(*seq)?;
+            body.write_u64::<BigEndian>(*ts)?;
+            (opcode::PONG, 0)
+        }
+        _ => {
+            body = serde_json::to_vec(proto)?;
+            (opcode::JSON, 0)
+        }
+    };
+    if body.len() > MAX_LEN as usize {
+        Err(Error::InvalidOperation(format!(
+            "Frame too long: {}",
+            body.len()
+        )))?;
+    }
+
+    let mut buf = Vec::with_capacity(body.len() + 8);
+    buf.write_u8(1)?;
+    buf.write_u8(op)?;
+    buf.write_u16::<BigEndian>(param)?;
+    buf.write_u32::<BigEndian>(body.len() as u32)?;
+    buf.append(&mut body);
+    Ok(buf)
+}
+
+fn write_establish(buf: &mut Vec<u8>, est: &Establish) -> Result<()> {
+    let (kind, src, dest) = match est {
+        Establish::Tcp(est) => (0, est.src, est.dest),
+        Establish::Udp(est) => (1, est.src, est.dest),
+    };
+    buf.write_u8(kind)?;
+    write_addr(buf, src)?;
+    write_addr(buf, dest)
+}
+
+fn read_establish(body: &mut Cursor<Vec<u8>>) -> Result<Establish> {
+    let kind = body.read_u8()?;
+    let src = read_addr(body)?;
+    let dest = read_addr(body)?;
+    match kind {
+        0 => Ok(Establish::Tcp(TcpEstablish { src, dest })),
+        1 => Ok(Establish::Udp(UdpEstablish { src, dest })),
+        _ => Err(Error::InvalidOperation(format!(
+            "invalid establish {}",
+            kind
+        ))),
+    }
+}
+
+fn write_addr(buf: &mut Vec<u8>, addr: SocketAddr) -> Result<()> {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            buf.write_u8(4)?;
+            buf.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buf.write_u8(6)?;
+            buf.extend_from_slice(&ip.octets());
+        }
+    }
+    buf.write_u16::<BigEndian>(addr.port())?;
+    Ok(())
+}
+
+fn read_addr(body: &mut Cursor<Vec<u8>>) -> Result<SocketAddr> {
+    let ip = match body.read_u8()? {
+        4 => {
+            let mut ip = [0u8; 4];
+            body.read_exact(&mut ip)?;
+            IpAddr::from(Ipv4Addr::from(ip))
+        }
+        6 => {
+            let mut ip = [0u8; 16];
+            body.read_exact(&mut ip)?;
+            IpAddr::from(Ipv6Addr::from(ip))
+        }
+        family => Err(Error::InvalidOperation(format!(
+            "invalid family {}",
+            family
+        )))?,
+    };
+    Ok(SocketAddr::new(ip, body.read_u16::<BigEndian>()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::io::Cursor as AsyncCursor;
+    use async_std::task;
+
+    fn round_trip(proto: &Protocol, max: usize) -> Result<Protocol> {
+        let frame = build_protocol(proto)?;
+        let mut header = [0u8; 4];
+        header.copy_from_slice(&frame[..4]);
+        let mut reader = AsyncCursor::new(frame[4..].to_vec());
+        task::block_on(parse(&mut reader, header, max))
+    }
+
+    fn establish() -> Establish {
+        Establish::Tcp(TcpEstablish {
+            src: "192.0.2.1:5000".parse().unwrap(),
+            dest: "[2001:db8::1]:80".parse().unwrap(),
+        })
+    }
+
+    #[test]
+    fn binary_frames() {
+        let r = round_trip(&Protocol::Ping(7), usize::MAX).unwrap();
+        assert!(matches!(r, Protocol::Ping(7)));
+        let r = round_trip(&Protocol::Window(3, 4096), usize::MAX).unwrap();
+        assert!(matches!(r, Protocol::Window(3, 4096)));
+        let r = round_trip(&Protocol::Fin(5), usize::MAX).unwrap();
+        assert!(matches!(r, Protocol::Fin(5)));
+        let r = round_trip(&Protocol::Rst(6), usize::MAX).unwrap();
+        assert!(matches!(r, Protocol::Rst(6)));
+        let r = round_trip(&Protocol::Heartbeat(1, 99), usize::MAX).unwrap();
+        assert!(matches!(r, Protocol::Heartbeat(1, 99)));
+        let r = round_trip(&Protocol::Pong(2, 100), usize::MAX).unwrap();
+        assert!(matches!(r, Protocol::Pong(2, 100)));
+    }
+
+    #[test]
+    fn establish_frames() {
+        match round_trip(&Protocol::Establish(establish(), 42), usize::MAX).unwrap() {
+            Protocol::Establish(est, 42) => assert_eq!(est, establish()),
+            r => panic!("{:?}", r),
+        }
+        match round_trip(&Protocol::Open(9, establish()), usize::MAX).unwrap() {
+            Protocol::Open(9, est) => assert_eq!(est, establish()),
+            r => panic!("{:?}", r),
+        }
+    }
+
+    #[test]
+    fn json_frame() {
+        let r = round_trip(&Protocol::ClientId("c1".to_string()), usize::MAX).unwrap();
+        assert!(matches!(r, Protocol::ClientId(id) if id == "c1"));
+    }
+
+    #[test]
+    fn body_over_chunks() {
+        let data: Vec<u8> = (0..CHUNK * 3 + 5).map(|i| i as u8).collect();
+        match round_trip(&Protocol::Data(1, data.clone()), usize::MAX).unwrap() {
+            Protocol::Data(1, read) => assert_eq!(read, data),
+            r => panic!("{:?}", r),
+        }
+    }
+
+    #[test]
+    fn body_over_max() {
+        let r = round_trip(&Protocol::Data(1, vec![0; 100]), 64);
+        assert!(matches!(r, Err(Error::InvalidOperation(_))));
+    }
+
+    #[test]
+    fn truncated_body() {
+        let frame = build_protocol(&Protocol::Window(3, 4096)).unwrap();
+        let mut header = [0u8; 4];
+        header.copy_from_slice(&frame[..4]);
+        let mut reader = AsyncCursor::new(frame[4..frame.len() - 1].to_vec());
+        let r = task::block_on(parse(&mut reader, header, usize::MAX));
+        assert!(matches!(r, Err(Error::Io(_))));
+    }
+
+    #[test]
+    fn bad_opcode() {
+        let mut reader = AsyncCursor::new(vec![0, 0, 0, 0]);
+        let r = task::block_on(parse(&mut reader, [1, 200, 0, 0], usize::MAX));
+        assert!(matches!(r, Err(Error::InvalidOperation(_))));
+    }
+}
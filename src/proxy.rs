@@ -0,0 +1,262 @@
+//! HAProxy PROXY protocol headers, carrying the address of a visitor to a
+//! service which only sees the connection of a proxy.
+
+use async_std::io;
+use futures::io::AsyncRead;
+use futures::AsyncReadExt;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::net::SocketAddr;
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// Longest v1 header, CRLF included
+const V1_MAX: usize = 107;
+
+/// Version of the PROXY header sent to a local service
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProxyProtocol {
+    /// Human readable
+    V1,
+    /// Binary
+    V2,
+}
+
+/// The header of a TCP connection from `src` to `dest`
+pub(crate) fn header(version: ProxyProtocol, src: SocketAddr, dest: SocketAddr) -> Vec<u8> {
+    // Both ends of the same family, IPv4 mapped into IPv6 if they differ
+    let (src, dest) = match (src, dest) {
+        (SocketAddr::V4(_), SocketAddr::V6(_)) => (mapped(src), dest),
+        (SocketAddr::V6(_), SocketAddr::V4(_)) => (src, mapped(dest)),
+        _ => (src, dest),
+    };
+    match version {
+        ProxyProtocol::V1 => {
+            let family = match src {
+                SocketAddr::V4(_) => "TCP4",
+                SocketAddr::V6(_) => "TCP6",
+            };
+            let (sip, sport, dip, dport) = (src.ip(), src.port(), dest.ip(), dest.port());
+            format!("PROXY {} {} {} {} {}\r\n", family, sip, dip, sport, dport).into_bytes()
+        }
+        ProxyProtocol::V2 => {
+            let mut header = V2_SIGNATURE.to_vec();
+            // Version 2, PROXY command
+            header.push(0x21);
+            let addrs = match (src.ip(), dest.ip()) {
+                (IpAddr::V4(sip), IpAddr::V4(dip)) => {
+                    header.push(0x11);
+                    [sip.octets().to_vec(), dip.octets().to_vec()].concat()
+                }
+                (sip, dip) => {
+                    header.push(0x21);
+                    [v6(sip).octets().to_vec(), v6(dip).octets().to_vec()].concat()
+                }
+            };
+            let len = addrs.len() as u16 + 4;
+            header.extend_from_slice(&len.to_be_bytes());
+            header.extend(addrs);
+            header.extend_from_slice(&src.port().to_be_bytes());
+            header.extend_from_slice(&dest.port().to_be_bytes());
+            header
+        }
+    }
+}
+
+fn mapped(addr: SocketAddr) -> SocketAddr {
+    (v6(addr.ip()), addr.port()).into()
+}
+
+fn v6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+/// Read the v1 or v2 header at the start of `stream`, and no more. Return the
+/// source and destination it carries, None for a connection of the proxy
+/// itself, as health checks.
+pub(crate) async fn read_header<R>(stream: &mut R) -> io::Result<Option<(SocketAddr, SocketAddr)>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut header = [0u8; 12];
+    stream.read_exact(&mut header).await?;
+    if header == V2_SIGNATURE {
+        return read_v2(stream).await;
+    }
+    if !header.starts_with(b"PROXY ") {
+        return Err(invalid("No PROXY header"));
+    }
+    let mut line = header.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX {
+            return Err(invalid("PROXY header too long"));
+        }
+        let mut byte = [0u8];
+        stream.read_exact(&mut byte).await?;
+        line.push(byte[0]);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2]).map_err(|_| invalid("Bad PROXY"))?;
+    parse_v1(line).ok_or_else(|| invalid("Bad PROXY header"))
+}
+
+fn parse_v1(line: &str) -> Option<Option<(SocketAddr, SocketAddr)>> {
+    let fields: Vec<_> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Some(None),
+        ["PROXY", "TCP4", sip, dip, sport, dport] | ["PROXY", "TCP6", sip, dip, sport, dport] => {
+            let src = (sip.parse::<IpAddr>().ok()?, sport.parse().ok()?).into();
+            let dest = (dip.parse::<IpAddr>().ok()?, dport.parse().ok()?).into();
+            Some(Some((src, dest)))
+        }
+        _ => None,
+    }
+}
+
+async fn read_v2<R>(stream: &mut R) -> io::Result<Option<(SocketAddr, SocketAddr)>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut head = [0u8; 4];
+    stream.read_exact(&mut head).await?;
+    let len = u16::from_be_bytes([head[2], head[3]]) as usize;
+    let mut body = vec![0u8; len];
+    stream.read_exact(&mut body).await?;
+    match head[0] {
+        0x20 => return Ok(None),
+        0x21 => {}
+        _ => return Err(invalid("Bad PROXY v2 command")),
+    }
+    let port = |at: usize| u16::from_be_bytes([body[at], body[at + 1]]);
+    match head[1] {
+        0x11 if len >= 12 => {
+            let sip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            let dip = Ipv4Addr::new(body[4], body[5], body[6], body[7]);
+            Ok(Some(((sip, port(8)).into(), (dip, port(10)).into())))
+        }
+        0x21 if len >= 36 => {
+            let octets = |at: usize| {
+                let mut ip = [0u8; 16];
+                ip.copy_from_slice(&body[at..at + 16]);
+                Ipv6Addr::from(ip)
+            };
+            let (sip, dip) = (octets(0), octets(16));
+            Ok(Some(((sip, port(32)).into(), (dip, port(34)).into())))
+        }
+        // Not TCP over IP, the proxy's own address is as good
+        _ => Ok(None),
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::io::Cursor;
+    use async_std::task;
+
+    type Addrs = io::Result<Option<(SocketAddr, SocketAddr)>>;
+
+    fn read(data: &[u8]) -> (Addrs, Vec<u8>) {
+        let mut stream = Cursor::new(data.to_vec());
+        task::block_on(async {
+            let addrs = read_header(&mut stream).await;
+            let mut rest = vec![];
+            let _ = stream.read_to_end(&mut rest).await;
+            (addrs, rest)
+        })
+    }
+
+    fn round_trip(version: ProxyProtocol, src: &str, dest: &str) -> (SocketAddr, SocketAddr) {
+        let (src, dest) = (src.parse().unwrap(), dest.parse().unwrap());
+        let mut data = header(version, src, dest);
+        data.extend_from_slice(b"GET /");
+        let (addrs, rest) = read(&data);
+        assert_eq!(rest, b"GET /");
+        addrs.unwrap().unwrap()
+    }
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn round_trips() {
+        let header = header(
+            ProxyProtocol::V1,
+            addr("192.0.2.1:5000"),
+            addr("10.0.0.1:80"),
+        );
+        assert_eq!(header, b"PROXY TCP4 192.0.2.1 10.0.0.1 5000 80\r\n");
+        for version in [ProxyProtocol::V1, ProxyProtocol::V2].iter() {
+            let addrs = round_trip(*version, "192.0.2.1:5000", "10.0.0.1:80");
+            assert_eq!(addrs, (addr("192.0.2.1:5000"), addr("10.0.0.1:80")));
+            let addrs = round_trip(*version, "[2001:db8::1]:5000", "[2001:db8::2]:80");
+            assert_eq!(
+                addrs,
+                (addr("[2001:db8::1]:5000"), addr("[2001:db8::2]:80"))
+            );
+        }
+    }
+
+    #[test]
+    fn mixed_families() {
+        for version in [ProxyProtocol::V1, ProxyProtocol::V2].iter() {
+            let addrs = round_trip(*version, "192.0.2.1:5000", "[2001:db8::2]:80");
+            assert_eq!(addrs.0, addr("[::ffff:192.0.2.1]:5000"));
+            assert_eq!(addrs.1, addr("[2001:db8::2]:80"));
+        }
+    }
+
+    #[test]
+    fn proxy_itself() {
+        let (addrs, rest) = read(b"PROXY UNKNOWN\r\nrest");
+        assert!(addrs.unwrap().is_none());
+        assert_eq!(rest, b"rest");
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+        local.extend_from_slice(b"rest");
+        let (addrs, rest) = read(&local);
+        assert!(addrs.unwrap().is_none());
+        assert_eq!(rest, b"rest");
+    }
+
+    #[test]
+    fn truncated_v2() {
+        let v2 = header(
+            ProxyProtocol::V2,
+            addr("192.0.2.1:5000"),
+            addr("10.0.0.1:80"),
+        );
+        for len in [13, 15, 16, v2.len() - 1].iter() {
+            let (addrs, _) = read(&v2[..*len]);
+            assert_eq!(addrs.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        }
+        // A body too short for its family carries no address
+        let mut short = V2_SIGNATURE.to_vec();
+        short.extend_from_slice(&[0x21, 0x11, 0x00, 0x04, 0, 0, 0, 0]);
+        assert!(read(&short).0.unwrap().is_none());
+    }
+
+    #[test]
+    fn bad_headers() {
+        let (addrs, _) = read(b"GET / HTTP/1.1\r\n\r\n");
+        assert_eq!(addrs.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let (addrs, _) = read(b"PROXY TCP4 1.2.3.4 5.6.7.8 x 80\r\n");
+        assert_eq!(addrs.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let long = format!("PROXY TCP4 {}\r\n", "1".repeat(V1_MAX));
+        let (addrs, _) = read(long.as_bytes());
+        assert_eq!(addrs.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let mut command = V2_SIGNATURE.to_vec();
+        command.extend_from_slice(&[0x22, 0x11, 0x00, 0x00]);
+        assert_eq!(
+            read(&command).0.unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+}
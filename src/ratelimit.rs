@@ -0,0 +1,323 @@
+//! Token buckets limiting the bytes of visitor sessions, each limit has a
+//! bucket for either way, shared by every session it applies to. Only the
+//! server limits, the client relays whatever the server lets through.
+
+use async_std::io;
+use futures::io::AsyncRead;
+use futures::io::AsyncWrite;
+use futures::ready;
+use futures_timer::Delay;
+use log::info;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::task::Context;
+use std::task::Poll;
+use std::time::Duration;
+use std::time::Instant;
+use thiserror::Error;
+
+/// Least bytes a read or write waits for, so that a throttled stream does
+/// not move them a few at a time
+const GRANT: u64 = 16 * 1024;
+
+/// Least burst, a datagram over the burst would never be let through
+pub const MIN_BURST: u64 = u16::MAX as u64;
+
+/// Bytes per second allowed each way, and how many may go at once after a
+/// quiet spell
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rate {
+    pub bytes: u64,
+    pub burst: u64,
+}
+
+#[derive(Debug, Error)]
+pub enum BadRate {
+    #[error("rate limit must be above zero")]
+    Zero,
+    #[error("rate burst {0} is below {}", MIN_BURST)]
+    Burst(u64),
+}
+
+impl Rate {
+    /// `bytes` per second, with a burst of as many but `MIN_BURST` at least
+    pub fn new(bytes: u64) -> Rate {
+        Rate {
+            bytes,
+            burst: bytes.max(MIN_BURST),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), BadRate> {
+        if self.bytes == 0 {
+            Err(BadRate::Zero)
+        } else if self.burst < MIN_BURST {
+            Err(BadRate::Burst(self.burst))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum Dir {
+    /// From the visitor
+    In,
+    /// To the visitor
+    Out,
+}
+
+/// A limit on the sessions of a listener, a client or the whole server
+pub(crate) struct Limiter {
+    name: String,
+    rate: Rate,
+    buckets: [Bucket; 2],
+}
+
+struct Bucket {
+    /// Tokens left, below zero once streams took more than granted, and
+    /// when they were counted
+    tokens: Mutex<(f64, Instant)>,
+    /// Bytes since the last report
+    used: AtomicU64,
+}
+
+impl Limiter {
+    pub(crate) fn new(name: String, rate: Rate) -> Arc<Limiter> {
+        let bucket = || Bucket {
+            tokens: Mutex::new((rate.burst as f64, Instant::now())),
+            used: AtomicU64::new(0),
+        };
+        Arc::new(Limiter {
+            name,
+            rate,
+            buckets: [bucket(), bucket()],
+        })
+    }
+
+    pub(crate) fn rate(&self) -> Rate {
+        self.rate
+    }
+
+    /// Bytes which may go `dir` now, up to `want`, or how long until then
+    fn grant(&self, dir: Dir, want: usize) -> Result<usize, Duration> {
+        let rate = self.rate.bytes.max(1) as f64;
+        let mut tokens = self.buckets[dir as usize].tokens.lock().unwrap();
+        let now = Instant::now();
+        let refill = now.duration_since(tokens.1).as_secs_f64() * rate;
+        *tokens = ((tokens.0 + refill).min(self.rate.burst as f64), now);
+        let need = (want as u64).min(GRANT).min(self.rate.burst.max(1)) as f64;
+        match tokens.0 >= need {
+            true => Ok(want.min(tokens.0 as usize)),
+            false => Err(Duration::from_secs_f64((need - tokens.0) / rate)),
+        }
+    }
+
+    /// Account `len` bytes gone `dir`
+    fn take(&self, dir: Dir, len: usize) {
+        let bucket = &self.buckets[dir as usize];
+        bucket.tokens.lock().unwrap().0 -= len as f64;
+        bucket.used.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    /// Whether a datagram of `len` bytes may go `dir` through all of
+    /// `limits` now, accounted if so
+    pub(crate) fn admit(limits: &[Arc<Limiter>], dir: Dir, len: usize) -> bool {
+        if limits.iter().any(|l| l.grant(dir, len) != Ok(len)) {
+            return false;
+        }
+        for limit in limits {
+            limit.take(dir, len);
+        }
+        true
+    }
+
+    /// Log the usage since the last report `elapsed` ago, if any
+    pub(crate) fn report(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64().max(1.0);
+        let rate = |dir: Dir| {
+            let used = self.buckets[dir as usize].used.swap(0, Ordering::Relaxed);
+            used as f64 / secs
+        };
+        let (rate_in, rate_out) = (rate(Dir::In), rate(Dir::Out));
+        if rate_in == 0.0 && rate_out == 0.0 {
+            return;
+        }
+        info!(
+            target: "shadow-peer",
+            "Rate limit {}: in {}, out {} of {}",
+            self.name,
+            per_sec(rate_in),
+            per_sec(rate_out),
+            per_sec(self.rate.bytes as f64)
+        );
+    }
+}
+
+fn per_sec(bytes: f64) -> String {
+    match bytes < 1024.0 * 1024.0 {
+        true => format!("{:.1} KiB/s", bytes / 1024.0),
+        false => format!("{:.1} MiB/s", bytes / 1024.0 / 1024.0),
+    }
+}
+
+/// A visitor stream held to `limits`, reads go in and writes go out
+pub(crate) struct Limited<S> {
+    inner: S,
+    limits: Vec<Arc<Limiter>>,
+    read_wait: Option<Delay>,
+    write_wait: Option<Delay>,
+}
+
+impl<S> Limited<S> {
+    pub(crate) fn new(inner: S, limits: Vec<Arc<Limiter>>) -> Limited<S> {
+        Limited {
+            inner,
+            limits,
+            read_wait: None,
+            write_wait: None,
+        }
+    }
+}
+
+/// Bytes which may go `dir` through all of `limits`, up to `want`, once
+/// every bucket holds enough
+fn poll_grant(
+    limits: &[Arc<Limiter>],
+    dir: Dir,
+    wait: &mut Option<Delay>,
+    cx: &mut Context<'_>,
+    want: usize,
+) -> Poll<usize> {
+    loop {
+        if let Some(delay) = wait {
+            ready!(Pin::new(delay).poll(cx));
+            *wait = None;
+        }
+        let mut len = want;
+        let mut delay = Duration::from_secs(0);
+        for limit in limits {
+            match limit.grant(dir, want) {
+                Ok(granted) => len = len.min(granted),
+                Err(until) => delay = delay.max(until),
+            }
+        }
+        if delay == Duration::from_secs(0) {
+            return Poll::Ready(len);
+        }
+        *wait = Some(Delay::new(delay));
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Limited<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let len = ready!(poll_grant(
+            &this.limits,
+            Dir::In,
+            &mut this.read_wait,
+            cx,
+            buf.len()
+        ));
+        let r = Pin::new(&mut this.inner).poll_read(cx, &mut buf[..len]);
+        if let Poll::Ready(Ok(len)) = r {
+            for limit in &this.limits {
+                limit.take(Dir::In, len);
+            }
+        }
+        r
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Limited<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let len = ready!(poll_grant(
+            &this.limits,
+            Dir::Out,
+            &mut this.write_wait,
+            cx,
+            buf.len()
+        ));
+        let r = Pin::new(&mut this.inner).poll_write(cx, &buf[..len]);
+        if let Poll::Ready(Ok(len)) = r {
+            for limit in &this.limits {
+                limit.take(Dir::Out, len);
+            }
+        }
+        r
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(bytes: u64, burst: u64) -> Arc<Limiter> {
+        Limiter::new("test".to_string(), Rate { bytes, burst })
+    }
+
+    #[test]
+    fn validate() {
+        assert_eq!(Rate::new(100).burst, MIN_BURST);
+        assert_eq!(Rate::new(1 << 20).burst, 1 << 20);
+        assert!(Rate::new(100).validate().is_ok());
+        assert!(matches!(Rate::new(0).validate(), Err(BadRate::Zero)));
+        let rate = Rate {
+            bytes: 100,
+            burst: GRANT,
+        };
+        assert!(matches!(rate.validate(), Err(BadRate::Burst(GRANT))));
+    }
+
+    #[test]
+    fn grant_up_to_burst() {
+        let limit = limiter(1024, MIN_BURST);
+        assert_eq!(limit.grant(Dir::In, 1 << 20), Ok(MIN_BURST as usize));
+        limit.take(Dir::In, MIN_BURST as usize);
+        // A full grant is waited for, at the rate
+        let wait = limit.grant(Dir::In, 1 << 20).unwrap_err();
+        assert!(wait > Duration::from_secs(15) && wait <= Duration::from_secs(16));
+        // The other way has its own bucket
+        assert_eq!(limit.grant(Dir::Out, 100), Ok(100));
+    }
+
+    #[test]
+    fn small_reads_wait_for_less() {
+        let limit = limiter(1024, MIN_BURST);
+        limit.take(Dir::Out, MIN_BURST as usize);
+        let wait = limit.grant(Dir::Out, 512).unwrap_err();
+        assert!(wait > Duration::from_millis(400) && wait <= Duration::from_millis(500));
+    }
+
+    #[test]
+    fn admit_datagrams() {
+        let limits = [limiter(1024, MIN_BURST), limiter(1 << 20, 1 << 20)];
+        assert!(Limiter::admit(&limits, Dir::In, MIN_BURST as usize));
+        // Neither bucket is taken from unless both admit
+        assert!(!Limiter::admit(&limits, Dir::In, 100));
+        let left = limits[1].grant(Dir::In, 1 << 20).unwrap();
+        assert!(left < (1 << 20) - MIN_BURST as usize / 2);
+        assert!(Limiter::admit(&limits, Dir::Out, 100));
+    }
+}
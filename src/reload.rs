@@ -0,0 +1,17 @@
+use futures::channel::mpsc::UnboundedSender;
+
+/// Applies new settings to a running server or client
+#[derive(Clone)]
+pub struct Reload<T> {
+    sender: UnboundedSender<T>,
+}
+
+impl<T> Reload<T> {
+    pub(crate) fn new(sender: UnboundedSender<T>) -> Reload<T> {
+        Reload { sender }
+    }
+
+    pub fn reload(&self, settings: T) {
+        let _ = self.sender.unbounded_send(settings);
+    }
+}
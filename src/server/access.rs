@@ -0,0 +1,180 @@
+use crate::protocol::net_proto::Listen;
+use crate::server::vhost::VHostListen;
+use crate::server::vhost::VisitorListen;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::RwLock;
+use thiserror::Error;
+
+/// A block of addresses, as `10.0.0.0/8` or `2001:db8::/32`, a bare address
+/// is a block of its own.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+#[derive(Debug, Error)]
+#[error("bad CIDR {0}")]
+pub struct BadCidr(String);
+
+impl FromStr for Cidr {
+    type Err = BadCidr;
+
+    fn from_str(s: &str) -> Result<Cidr, BadCidr> {
+        let bad = || BadCidr(s.to_string());
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.trim().parse().map_err(|_| bad())?;
+        let bits = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix = match prefix {
+            Some(prefix) => prefix.trim().parse().map_err(|_| bad())?,
+            None => bits,
+        };
+        match prefix <= bits {
+            true => Ok(Cidr { addr, prefix }),
+            false => Err(bad()),
+        }
+    }
+}
+
+impl Cidr {
+    /// IPv4 addresses mapped into IPv6, as seen on dual stack listeners,
+    /// match IPv4 blocks.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, canonical(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                same_prefix(u32::from(net).into(), u32::from(ip).into(), self.prefix, 32)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                same_prefix(net.into(), ip.into(), self.prefix, 128)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The IPv4 address behind an IPv4 mapped IPv6 one
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, hi, lo] => {
+                IpAddr::V4(Ipv4Addr::from((u32::from(hi) << 16) | u32::from(lo)))
+            }
+            _ => ip,
+        },
+        IpAddr::V4(_) => ip,
+    }
+}
+
+fn same_prefix(a: u128, b: u128, prefix: u8, bits: u8) -> bool {
+    prefix == 0 || (a ^ b) >> (bits - prefix) == 0
+}
+
+/// Visitors let in by their address. Those in `deny` are not, if `allow`
+/// is not empty only those in it are.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Access {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+}
+
+impl Access {
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        let allowed = self.allow.is_empty() || self.allow.iter().any(|c| c.contains(ip));
+        allowed && !self.deny.iter().any(|c| c.contains(ip))
+    }
+}
+
+/// Access to all visitor listeners and to some of them, a visitor must be
+/// let in by both.
+#[derive(Clone, Default, PartialEq)]
+pub struct AccessLists {
+    pub global: Access,
+    pub listen: Vec<(Listen, Access)>,
+    pub vhost: Vec<(VHostListen, Access)>,
+}
+
+/// The access lists of a running server
+#[derive(Clone, Default)]
+pub(in crate::server) struct Gate(Arc<RwLock<(Access, HashMap<VisitorListen, Access>)>>);
+
+impl Gate {
+    pub(in crate::server) fn apply(&self, lists: AccessLists) {
+        let listen = lists.listen.into_iter().map(|(l, a)| (l.into(), a));
+        let vhost = lists.vhost.into_iter().map(|(l, a)| (l.into(), a));
+        *self.0.write().unwrap() = (lists.global, listen.chain(vhost).collect());
+    }
+
+    /// Whether a visitor from `ip` may connect to `listen`
+    pub(in crate::server) fn permits(&self, listen: &VisitorListen, ip: IpAddr) -> bool {
+        let lists = self.0.read().unwrap();
+        let listen = lists.1.get(listen).map(|a| a.permits(ip)).unwrap_or(true);
+        listen && lists.0.permits(ip)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(cidr("10.0.0.0/8").prefix, 8);
+        assert_eq!(cidr(" 10.1.2.3 ").prefix, 32);
+        assert_eq!(cidr("2001:db8::/32").prefix, 32);
+        assert_eq!(cidr("::1").prefix, 128);
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("2001:db8::/129".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/x".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn contains() {
+        assert!(cidr("10.0.0.0/8").contains(ip("10.255.0.1")));
+        assert!(!cidr("10.0.0.0/8").contains(ip("11.0.0.1")));
+        assert!(cidr("192.168.1.7").contains(ip("192.168.1.7")));
+        assert!(!cidr("192.168.1.7").contains(ip("192.168.1.8")));
+        assert!(cidr("0.0.0.0/0").contains(ip("203.0.113.9")));
+        assert!(cidr("2001:db8::/32").contains(ip("2001:db8:1::5")));
+        assert!(!cidr("2001:db8::/32").contains(ip("2001:db9::5")));
+        assert!(!cidr("0.0.0.0/0").contains(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn ipv4_mapped() {
+        assert!(cidr("10.0.0.0/8").contains(ip("::ffff:10.1.2.3")));
+        assert!(!cidr("10.0.0.0/8").contains(ip("::ffff:11.1.2.3")));
+        // IPv4 compatible addresses are left as IPv6
+        assert!(!cidr("10.0.0.0/8").contains(ip("::10.1.2.3")));
+    }
+
+    #[test]
+    fn permits() {
+        let access = Access {
+            allow: vec![cidr("10.0.0.0/8")],
+            deny: vec![cidr("10.0.13.0/24")],
+        };
+        assert!(access.permits(ip("10.0.12.1")));
+        assert!(!access.permits(ip("10.0.13.1")));
+        assert!(!access.permits(ip("192.0.2.1")));
+        assert!(Access::default().permits(ip("192.0.2.1")));
+    }
+}
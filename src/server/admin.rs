@@ -0,0 +1,190 @@
+//! JSON over HTTP, for inspecting and controlling a running server
+//!
+//! GET  /clients                           connected clients, one per connection
+//! POST /clients/{id}/kick                 disconnect all connections of a client
+//! GET  /listeners                         listeners and their state
+//! POST /listeners/{proto}/{addr}/enable   start a configured listener
+//! POST /listeners/{proto}/{addr}/disable  stop it, until enabled again
+//! GET  /sessions                          visitor sessions being served
+//!
+//! With a token, each request must carry it as `Authorization: Bearer`. The
+//! admin is only served off loopback with a token.
+
+use super::client::StreamShare;
+use super::kick;
+use super::ListenState;
+use super::ListenerId;
+use crate::http;
+use crate::http::Request;
+use crate::http::Response;
+use crate::protocol::net_proto::Listen;
+use async_std::net::TcpListener;
+use futures::channel::mpsc::UnboundedSender;
+use futures::channel::oneshot;
+use serde_json::json;
+use serde_json::Value;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+
+/// Carried out by the running server on behalf of the admin
+pub(in crate::server) enum Command {
+    /// Start or stop a listener, answer false if it is not configured
+    Enable(Listen, bool, oneshot::Sender<bool>),
+}
+
+#[derive(Clone)]
+pub(in crate::server) struct Admin {
+    pub(in crate::server) share: StreamShare,
+    pub(in crate::server) command: UnboundedSender<Command>,
+    pub(in crate::server) token: Option<String>,
+}
+
+/// Serve the admin until the server closes
+pub(in crate::server) async fn serve(listener: TcpListener, admin: Admin) {
+    let closing = admin.share.closing.clone();
+    http::serve(listener, "Admin", closing, move |req| {
+        let admin = admin.clone();
+        async move {
+            let (status, body) = match admin.authorized(&req) {
+                true => admin.route(&req).await,
+                false => (401, error("Unauthorized")),
+            };
+            Response::json(status, body)
+        }
+    })
+    .await
+}
+
+impl Admin {
+    fn authorized(&self, req: &Request) -> bool {
+        let (token, given) = match (&self.token, &req.token) {
+            (None, _) => return true,
+            (Some(token), Some(given)) => (token.as_bytes(), given.as_bytes()),
+            (Some(_), None) => return false,
+        };
+        // In constant time
+        let diff = token.iter().zip(given).fold(0, |d, (a, b)| d | (a ^ b));
+        token.len() == given.len() && diff == 0
+    }
+
+    async fn route(&self, req: &Request) -> (u16, Value) {
+        let path: Vec<_> = req.path.iter().map(String::as_str).collect();
+        match (req.method.as_str(), path.as_slice()) {
+            ("GET", ["clients"]) => (200, self.clients().await),
+            ("POST", ["clients", id, "kick"]) => self.kick(id).await,
+            ("GET", ["listeners"]) => (200, self.listeners()),
+            ("POST", ["listeners", proto, addr, "enable"]) => self.enable(proto, addr, true).await,
+            ("POST", ["listeners", proto, addr, "disable"]) => {
+                self.enable(proto, addr, false).await
+            }
+            ("GET", ["sessions"]) => (200, self.sessions()),
+            _ => (404, error("Not found")),
+        }
+    }
+
+    async fn clients(&self) -> Value {
+        let cli = self.share.cli.read().await;
+        let clients = cli
+            .iter()
+            .flat_map(|(id, i)| i.clients.iter().map(move |c| (id, i, c)));
+        let clients = clients.map(|(id, instances, client)| {
+            let registered = instances.listeners.iter();
+            let registered = registered.filter(|r| r.owned_by(&client.pool));
+            let heartbeat = client.heartbeat.lock().unwrap();
+            json!({
+                "id": id,
+                "peer": client.peer,
+                "online_secs": client.since.elapsed().as_secs(),
+                "last_ping_secs": heartbeat.last_pong().map(|t| t.elapsed().as_secs()),
+                "rtt_ms": heartbeat.rtt().map(|rtt| rtt.as_millis() as u64),
+                "jitter_ms": heartbeat.jitter().as_millis() as u64,
+                "missed_pings": heartbeat.missed(),
+                "multiplex": client.mux.is_some(),
+                "registered": registered.count(),
+                "sessions": client.active.load(Ordering::Relaxed),
+            })
+        });
+        Value::Array(clients.collect())
+    }
+
+    async fn kick(&self, id: &str) -> (u16, Value) {
+        match kick(&self.share, &id.to_string()).await {
+            true => (200, json!({ "kicked": id })),
+            false => (404, error("No such client")),
+        }
+    }
+
+    fn listeners(&self) -> Value {
+        let state = self.share.listeners.state();
+        let listeners = state.into_iter().map(|(id, state)| {
+            let (kind, proto, listen, client) = match &id {
+                ListenerId::Client(socket) => ("client", None, socket, None),
+                ListenerId::Visitor(Listen::Tcp(socket), cid) => {
+                    ("visitor", Some("tcp"), socket, Some(cid))
+                }
+                ListenerId::Visitor(Listen::Udp(socket), cid) => {
+                    ("visitor", Some("udp"), socket, Some(cid))
+                }
+                ListenerId::VHost(listen) => {
+                    ("vhost", Some(listen.proto()), &listen.socket(), None)
+                }
+            };
+            let (state, error) = match state {
+                ListenState::Up => ("up", None),
+                ListenState::Failed(e) => ("failed", Some(e)),
+                ListenState::Disabled => ("disabled", None),
+            };
+            json!({
+                "kind": kind,
+                "proto": proto,
+                "listen": listen,
+                "client": client,
+                "state": state,
+                "error": error,
+            })
+        });
+        Value::Array(listeners.collect())
+    }
+
+    fn sessions(&self) -> Value {
+        let mut sessions = self.share.sessions.list();
+        sessions.sort_by_key(|(id, _)| *id);
+        let sessions = sessions.into_iter().map(|(id, visit)| {
+            json!({
+                "id": id,
+                "client": visit.client,
+                "proto": visit.est.proto().to_string(),
+                "visitor": visit.est.src(),
+                "listen": visit.est.dest(),
+                "secs": visit.start.elapsed().as_secs(),
+                "open": visit.open,
+            })
+        });
+        Value::Array(sessions.collect())
+    }
+
+    async fn enable(&self, proto: &str, addr: &str, enable: bool) -> (u16, Value) {
+        let socket: SocketAddr = match addr.parse() {
+            Ok(socket) => socket,
+            Err(_) => return (400, error("Bad address")),
+        };
+        let listen = match proto {
+            "tcp" => Listen::Tcp(socket),
+            "udp" => Listen::Udp(socket),
+            _ => return (400, error("Bad protocol")),
+        };
+        let (send, recv) = oneshot::channel();
+        let _ = self
+            .command
+            .unbounded_send(Command::Enable(listen, enable, send));
+        match recv.await {
+            Ok(true) => (200, json!({ "listen": socket, "enabled": enable })),
+            Ok(false) => (404, error("No such listener")),
+            Err(_) => (503, error("Server is closing")),
+        }
+    }
+}
+
+fn error(msg: &str) -> Value {
+    json!({ "error": msg })
+}
@@ -1,13 +1,63 @@
 pub(in crate::server) use self::tcp::tcp;
+use super::access::Gate;
+use super::count_clients;
+use super::limits::Limiters;
+use super::listeners::Listeners;
+use super::pool::Pool;
+use super::pool::POOL_MAX;
+use super::register;
 use super::reqmap::ReqMapMessage;
 use super::reqmap::ReqStat;
+use super::Balance;
 use super::Client;
 use super::ClientMap;
+use super::Instances;
+use super::Policy;
 use super::ReqMapSender;
+use super::Sessions;
+use super::Timeouts;
+use super::VHost;
+use super::VHostListen;
+use crate::metrics::Metrics;
+use crate::protocol::net_proto::Listen;
+use crate::protocol::ClientId;
+use crate::shutdown::Shutdown;
+use crate::tls::TlsAcceptor;
+use async_std::sync::Arc;
+use async_std::sync::RwLock;
+use std::collections::HashMap;
+use std::collections::HashSet;
 use std::net::SocketAddr;
 
 mod tcp;
 
 pub enum CliListen {
     Tcp(SocketAddr),
+    Tls(SocketAddr, TlsAcceptor),
+}
+
+/// Shared by the listeners of a running server and their connections
+#[derive(Clone)]
+pub(in crate::server) struct StreamShare {
+    pub(in crate::server) cli: ClientMap,
+    pub(in crate::server) req: ReqMapSender,
+    pub(in crate::server) listeners: Listeners,
+    pub(in crate::server) policy: Arc<RwLock<HashMap<ClientId, Policy>>>,
+    pub(in crate::server) secret: Arc<RwLock<HashMap<ClientId, String>>>,
+    pub(in crate::server) sessions: Sessions,
+    pub(in crate::server) metrics: Metrics,
+    pub(in crate::server) limiters: Limiters,
+    /// Visitors let in
+    pub(in crate::server) gate: Gate,
+    /// Visitor listeners behind a load balancer sending PROXY headers
+    pub(in crate::server) proxied: Arc<RwLock<HashSet<Listen>>>,
+    /// Routes of the listeners shared by clients
+    pub(in crate::server) vhosts: Arc<RwLock<HashMap<VHostListen, Vec<VHost>>>>,
+    pub(in crate::server) timeouts: Timeouts,
+    /// Spreads visitors among the connections of a client
+    pub(in crate::server) balance: Balance,
+    /// No login is accepted once shut down
+    pub(in crate::server) shutdown: Shutdown,
+    /// Close control connections
+    pub(in crate::server) closing: Shutdown,
 }
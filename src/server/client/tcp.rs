@@ -1,18 +1,40 @@
+use super::count_clients;
+use super::register;
 use super::Client;
 use super::ClientMap;
+use super::Instances;
+use super::Pool;
 use super::ReqMapMessage;
-use super::ReqMapSender;
 use super::ReqStat;
-use crate::error::err_exit;
+use super::StreamShare;
+use super::POOL_MAX;
 use crate::error::Error;
 use crate::error::Result;
+use crate::heartbeat::Heartbeat;
+use crate::metrics::Gauge;
+use crate::metrics::PING_JITTER;
+use crate::metrics::PING_RTT;
+use crate::metrics::UNMATCHED_WORKERS;
+use crate::mux::Session;
+use crate::protocol::auth;
+use crate::protocol::auth::Token;
+use crate::protocol::caps;
+use crate::protocol::negotiate;
 use crate::protocol::net_proto::Establish;
+use crate::protocol::net_proto::Remote;
+use crate::protocol::protocol_stream;
+use crate::protocol::read_login_timeout;
 use crate::protocol::read_protocol_timeout;
 use crate::protocol::write_protocol;
 use crate::protocol::ClientId;
+use crate::protocol::Hello;
 use crate::protocol::Protocol;
-use crate::protocol::CURRENT_VERSION;
-use crate::utils::current_time16;
+use crate::protocol::BASE_VERSION;
+use crate::shutdown::Shutdown;
+use crate::stream::BoxStream;
+use crate::tls::TlsAcceptor;
+use crate::utils::Backoff;
+use async_std::io::timeout;
 use async_std::net::TcpListener;
 use async_std::net::TcpStream;
 use async_std::stream::StreamExt;
@@ -21,84 +43,301 @@ use async_std::task;
 use futures::channel::mpsc;
 use futures::channel::mpsc::UnboundedReceiver;
 use futures::channel::oneshot;
+use futures::io::WriteHalf;
+use futures::AsyncReadExt;
 use futures::FutureExt;
 use futures_timer::Delay;
-use std::collections::HashSet;
-use std::io;
+use log::warn;
 use std::net::SocketAddr;
 use std::result::Result as StdResult;
+use std::sync::Mutex;
 use std::time::Duration;
+use std::time::Instant;
 
-struct StreamShare {
-    cli: ClientMap,
-    idset: Arc<HashSet<ClientId>>,
-    req: ReqMapSender,
-}
-
+/// Listen for clients, TLS is used if `tls` is given.
 pub(in crate::server) async fn tcp(
-    socket: SocketAddr,
-    cli: ClientMap,
-    req: ReqMapSender,
-    idset: Arc<HashSet<ClientId>>,
+    tcp: TcpListener,
+    tls: Option<TlsAcceptor>,
+    share: StreamShare,
 ) -> Result<()> {
-    let port = socket.port() as u32;
-    let tcp = TcpListener::bind(socket).await?;
+    let port = tcp.local_addr()?.port() as u32;
+    let proto = if tls.is_some() { "TLS" } else { "TCP" };
     let mut tcp = tcp.incoming();
-    let share = Arc::new(StreamShare { cli, idset, req });
-    while let Some(stream) = tcp.next().await {
+    let share = Arc::new(share);
+    let mut backoff = Backoff::new();
+    loop {
+        let stream = futures::select! {
+            stream = tcp.next().fuse() => stream,
+            _ = share.closing.wait().fuse() => return Ok(()),
+        };
+        let stream = match stream {
+            Some(Ok(stream)) => stream,
+            None => break,
+            Some(Err(e)) => {
+                warn!(target: "shadow-peer", "{} accept on port {}: {}", proto, port, e);
+                backoff.wait().await;
+                continue;
+            }
+        };
+        backoff.reset();
         let share = share.clone();
+        let tls = tls.clone();
         task::spawn(async move {
-            match init(&share, stream).await {
-                Some(ConnInit::Control(tcp, recv, id)) => {
-                    controller(tcp, recv).await;
-                    share.cli.write().await.remove(&id);
+            let (stream, peer) = match accept(stream, tls, share.timeouts.read).await {
+                Some(r) => r,
+                None => return,
+            };
+            match init(&share, stream, peer).await {
+                Some(ConnInit::Control(tcp, mut login)) => {
+                    controller(tcp, &mut login, &share).await;
+                    logout(&share, &login).await;
                 }
-                Some(ConnInit::Worker(tcp, est)) => worker(tcp, est, &share.req).await,
+                Some(ConnInit::Worker(tcp, est, token)) => worker(tcp, est, token, &share).await,
+                Some(ConnInit::Park(tcp, id, token)) => park(tcp, id, token, &share.cli).await,
                 None => {}
             };
         });
     }
-    err_exit(66, Error::ListenFail("TCP", port))
+    Err(Error::ListenFail(proto, port))
 }
 
 struct Controller {
-    stream: Arc<TcpStream>,
-    last_recv: u16,
+    writer: WriteHalf<BoxStream>,
+    version: u8,
+    mux: Option<Arc<Session>>,
+    heartbeat: Arc<Mutex<Heartbeat>>,
+    /// The client pings with `Ping`, not `Heartbeat`
+    legacy: bool,
+    rtt: Gauge,
+    jitter: Gauge,
+}
+
+/// An authenticated control connection
+struct Login {
+    id: ClientId,
+    recv: UnboundedReceiver<Protocol>,
+    mux: Option<Arc<Session>>,
+    /// Tells this login from the others of the same client
+    pool: Arc<Pool>,
+    version: u8,
+    caps: u32,
+    /// Closes the connection once the client is kicked
+    kick: Shutdown,
+    heartbeat: Arc<Mutex<Heartbeat>>,
+    peer: SocketAddr,
 }
 
 enum ConnInit {
-    Control(Controller, UnboundedReceiver<Protocol>, ClientId),
-    Worker(TcpStream, Establish),
+    Control(BoxStream, Login),
+    Worker(BoxStream, Establish, Token),
+    Park(BoxStream, ClientId, Token),
 }
 
-async fn init(share: &StreamShare, stream: StdResult<TcpStream, io::Error>) -> Option<ConnInit> {
-    let mut stream = stream.ok()?;
-    let r = match read_protocol_timeout(&mut stream, 10).await.ok()? {
+async fn accept(
+    stream: TcpStream,
+    tls: Option<TlsAcceptor>,
+    tmout: Duration,
+) -> Option<(BoxStream, SocketAddr)> {
+    let peer = stream.peer_addr().ok()?;
+    let tls = match tls {
+        Some(tls) => tls,
+        None => return Some((Box::new(stream), peer)),
+    };
+    match timeout(tmout, tls.accept(stream)).await {
+        Ok(stream) => Some((Box::new(stream), peer)),
+        Err(e) => {
+            warn!(target: "shadow-peer", "TLS handshake with {} failed: {}", peer, e);
+            None
+        }
+    }
+}
+
+async fn init(share: &StreamShare, mut stream: BoxStream, peer: SocketAddr) -> Option<ConnInit> {
+    let mut proto = read_login_timeout(&mut stream, share.timeouts.read)
+        .await
+        .ok()?;
+    let mut caps = 0;
+    let mut version = BASE_VERSION;
+    if let Protocol::Hello(hello) = proto {
+        caps = hello.caps & caps::SUPPORTED;
+        version = negotiate(&hello.versions);
+        let hello = Hello {
+            caps,
+            versions: vec![version],
+            max_pool: POOL_MAX as u32,
+        };
+        if !write_wrap(&mut stream, BASE_VERSION, &Protocol::Hello(hello)).await {
+            return None;
+        }
+        proto = read_login_timeout(&mut stream, share.timeouts.read)
+            .await
+            .ok()?;
+    }
+    let r = match proto {
+        Protocol::ClientId(_) | Protocol::Park(..) if share.shutdown.is_shutdown() => return None,
         Protocol::ClientId(id) => {
             let id = ClientId::from(id);
-            if !share.idset.contains(&id) {
+            if !authenticate(share, &mut stream, &id, version).await {
+                warn!(target: "shadow-peer", "Client {} auth failed from {}", id, peer);
                 return None;
             }
             let (send, recv) = mpsc::unbounded();
-            let client = Client { estab_sender: send };
-            let controller = Controller {
-                stream: Arc::new(stream),
-                last_recv: current_time16(),
+            let mux = match caps & caps::MULTIPLEX {
+                0 => None,
+                _ => Some(Session::new(send.clone())),
             };
-            share.cli.write().await.insert(id.clone(), client);
-            ConnInit::Control(controller, recv, id)
+            let pool = Arc::new(Pool::new());
+            let kick = Shutdown::new();
+            let missed = share.timeouts.missed_pings;
+            let heartbeat = Arc::new(Mutex::new(Heartbeat::new(missed)));
+            let client = Client {
+                estab_sender: send,
+                mux: mux.clone(),
+                pool: pool.clone(),
+                peer,
+                since: Instant::now(),
+                heartbeat: heartbeat.clone(),
+                active: Default::default(),
+                kick: kick.clone(),
+            };
+            {
+                let mut cli = share.cli.write().await;
+                let instances = cli.entry(id.clone());
+                let instances = instances.or_insert_with(|| Instances::new(share.balance));
+                instances.clients.push(client);
+                count_clients(&share.metrics, &cli);
+            }
+            let login = Login {
+                id,
+                recv,
+                mux,
+                pool,
+                version,
+                caps,
+                kick,
+                heartbeat,
+                peer,
+            };
+            if !setup(share, &mut stream, &login).await {
+                logout(share, &login).await;
+                return None;
+            }
+            ConnInit::Control(stream, login)
         }
-        Protocol::Establish(est) => ConnInit::Worker(stream, est),
+        Protocol::Establish(est, token) => ConnInit::Worker(stream, est, token),
+        Protocol::Park(id, token) => ConnInit::Park(stream, id, token),
         _ => return None,
     };
     Some(r)
 }
 
-async fn controller(mut c: Controller, mut recv: UnboundedReceiver<Protocol>) {
-    const PING_TMOUT: u64 = 5;
+/// Hand out the pool token and open the registered listeners
+async fn setup(share: &StreamShare, stream: &mut BoxStream, login: &Login) -> bool {
+    let (version, caps) = (login.version, login.caps);
+    let proto = Protocol::Pool(login.pool.token);
+    if caps & caps::POOL != 0 && !write_wrap(stream, version, &proto).await {
+        return false;
+    }
+    if caps & caps::REGISTER == 0 {
+        return true;
+    }
+    let remotes = match read_protocol_timeout(stream, share.timeouts.read).await {
+        Ok(Protocol::Register(remotes)) => remotes,
+        _ => return false,
+    };
+    match register(share, &login.id, &login.pool, remotes).await {
+        Some(result) => write_wrap(stream, version, &Protocol::Registered(result)).await,
+        None => false,
+    }
+}
+
+/// Register the listeners the connection of client `id` logged in with
+/// `pool` asks for, unless it is gone meanwhile. Those it asks no more are
+/// stopped, unless another connection of the client asks for them.
+async fn register(
+    share: &StreamShare,
+    id: &ClientId,
+    pool: &Arc<Pool>,
+    remotes: Vec<Remote>,
+) -> Option<Vec<StdResult<SocketAddr, String>>> {
+    let mut cli = share.cli.write().await;
+    let instances = cli.get_mut(id)?;
+    instances.get_mut(pool)?;
+    Some(register::register(remotes, id, share, pool, &mut instances.listeners).await)
+}
+
+async fn logout(share: &StreamShare, login: &Login) {
+    forget(share, &login.id, &login.pool).await;
+    let peer = login.peer.to_string();
+    let labels = [("client", login.id.as_str()), ("peer", peer.as_str())];
+    share.metrics.remove(&PING_RTT, &labels);
+    share.metrics.remove(&PING_JITTER, &labels);
+    if let Some(mux) = &login.mux {
+        mux.close();
+    }
+}
+
+/// Forget the connection of the client logged in with `pool`, no visitor is
+/// sent to it from now on. Its other connections are left alone.
+async fn forget(share: &StreamShare, id: &ClientId, pool: &Arc<Pool>) {
+    let released = {
+        let mut cli = share.cli.write().await;
+        let mut released = vec![];
+        if let Some(instances) = cli.get_mut(id) {
+            released = instances.forget(pool);
+            if instances.clients.is_empty() {
+                cli.remove(id);
+            }
+        }
+        count_clients(&share.metrics, &cli);
+        released
+    };
+    for listener in released {
+        listener.close().await;
+    }
+}
+
+async fn authenticate(
+    share: &StreamShare,
+    stream: &mut BoxStream,
+    id: &ClientId,
+    version: u8,
+) -> bool {
+    let secret = match share.secret.read().await.get(id) {
+        Some(secret) => secret.clone(),
+        None => return false,
+    };
+    let nonce = auth::nonce();
+    if !write_wrap(stream, version, &Protocol::Challenge(nonce.clone())).await {
+        return false;
+    }
+    match read_login_timeout(stream, share.timeouts.read).await {
+        Ok(Protocol::Response(sign)) => auth::verify(&secret, id, &nonce, &sign),
+        _ => false,
+    }
+}
+
+async fn controller(stream: BoxStream, login: &mut Login, share: &StreamShare) {
+    let (reader, writer) = stream.split();
+    // Each connection of the client pings on its own
+    let peer = login.peer.to_string();
+    let labels = [("client", login.id.as_str()), ("peer", peer.as_str())];
+    let mut c = Controller {
+        writer,
+        version: login.version,
+        mux: login.mux.clone(),
+        heartbeat: login.heartbeat.clone(),
+        legacy: login.caps & caps::HEARTBEAT == 0,
+        rtt: share.metrics.gauge(&PING_RTT, &labels),
+        jitter: share.metrics.gauge(&PING_JITTER, &labels),
+    };
+    let recv = &mut login.recv;
+    let mut reader = Box::pin(protocol_stream(reader, share.timeouts.read));
     let mut send_fut = recv.next().fuse();
-    let mut recv_fut = Box::pin(read_wrap(c.stream.clone()).fuse());
-    let mut ping_timer = Delay::new(Duration::from_secs(PING_TMOUT)).fuse();
+    let mut recv_fut = reader.next().fuse();
+    let mut ping_timer = Delay::new(share.timeouts.ping).fuse();
+    let mut closing = Box::pin(share.closing.wait().fuse());
+    let mut kicked = Box::pin(login.kick.wait().fuse());
     loop {
         futures::select! {
             send = send_fut => {
@@ -106,55 +345,110 @@ async fn controller(mut c: Controller, mut recv: UnboundedReceiver<Protocol>) {
                     Some(proto) => proto,
                     None => return,
                 };
-                if !write_wrap(&mut &*c.stream, &proto).await {
+                if !write_wrap(&mut c.writer, c.version, &proto).await {
                     return;
                 }
                 send_fut = recv.next().fuse();
             },
             recv = recv_fut => {
                 let proto = match recv {
-                    Ok(proto) => proto,
-                    Err(_) => return,
+                    Some(Ok(proto)) => proto,
+                    _ => return,
                 };
-                handle_recv(&mut c, proto);
-                recv_fut = Box::pin(read_wrap(c.stream.clone()).fuse());
+                recv_fut = reader.next().fuse();
+                match proto {
+                    Protocol::Register(remotes) if login.caps & caps::REGISTER != 0 => {
+                        let result = register(share, &login.id, &login.pool, remotes).await;
+                        let proto = Protocol::Registered(result.unwrap_or_default());
+                        if !write_wrap(&mut c.writer, c.version, &proto).await {
+                            return;
+                        }
+                        continue;
+                    }
+                    Protocol::Goodbye => forget(share, &login.id, &login.pool).await,
+                    _ => {}
+                }
+                if let Some(pong) = handle_recv(&mut c, proto) {
+                    if !write_wrap(&mut c.writer, c.version, &pong).await {
+                        return;
+                    }
+                }
             },
             _ = ping_timer => {
-                let proto = Protocol::Ping(current_time16());
-                if !write_wrap(&mut &*c.stream, &proto).await {
+                let ping = match c.heartbeat.lock().unwrap().ping(c.legacy) {
+                    Some(ping) => ping,
+                    None => {
+                        let e = Error::Unresponsive(share.timeouts.missed_pings);
+                        warn!(target: "shadow-peer", "Client {}: {}", login.id, e);
+                        return;
+                    }
+                };
+                if !write_wrap(&mut c.writer, c.version, &ping).await {
                     return;
                 }
-                ping_timer = Delay::new(Duration::from_secs(PING_TMOUT)).fuse();
+                ping_timer = Delay::new(share.timeouts.ping).fuse();
             },
+            _ = closing => return,
+            _ = kicked => return,
         }
     }
 }
 
-fn handle_recv(c: &mut Controller, proto: Protocol) -> bool {
-    c.last_recv = current_time16();
-    match proto {
-        Protocol::Ping(_) => true,
-        _ => false,
-    }
+/// Account pongs, return the answer to a ping if any
+fn handle_recv(c: &mut Controller, proto: Protocol) -> Option<Protocol> {
+    let proto = match &c.mux {
+        Some(mux) => mux.dispatch(proto)?,
+        None => proto,
+    };
+    let mut heartbeat = c.heartbeat.lock().unwrap();
+    let rtt = match proto {
+        Protocol::Heartbeat(seq, ts) => return Some(Protocol::Pong(seq, ts)),
+        Protocol::Pong(seq, ts) => heartbeat.pong(seq, ts)?,
+        Protocol::Ping(seq) => heartbeat.echo(seq)?,
+        _ => return None,
+    };
+    c.rtt.set(rtt.as_secs_f64());
+    c.jitter.set(heartbeat.jitter().as_secs_f64());
+    None
 }
 
-async fn worker(stream: TcpStream, est: Establish, req: &ReqMapSender) {
+async fn worker(stream: BoxStream, est: Establish, token: Token, share: &StreamShare) {
     let (send, recv) = oneshot::channel();
-    let msg = ReqMapMessage::Take(send);
-    if let Err(_) = req.unbounded_send((est, msg)) {
+    let msg = ReqMapMessage::Take(token, send);
+    if share.req.unbounded_send((est, msg)).is_err() {
         return;
     }
     let stat = match recv.await {
-        Ok(Some(ReqStat::Syn(stat))) => stat,
-        _ => return,
+        Ok(Some(ReqStat::Syn(_, stat))) => stat,
+        Ok(None) => {
+            share.metrics.counter(&UNMATCHED_WORKERS, &[]).inc();
+            return;
+        }
+        Err(_) => return,
     };
     let _ = stat.send(stream);
 }
 
-async fn write_wrap(s: &mut &TcpStream, proto: &Protocol) -> bool {
-    write_protocol(s, CURRENT_VERSION, proto).await.is_ok()
+async fn park(stream: BoxStream, id: ClientId, token: Token, cli: &ClientMap) {
+    let pool = cli.read().await.get(&id).and_then(|instances| {
+        let mut clients = instances.clients.iter();
+        clients
+            .find(|c| c.pool.token == token)
+            .map(|c| c.pool.clone())
+    });
+    let pool = match pool {
+        Some(pool) => pool,
+        None => {
+            warn!(target: "shadow-peer", "Invalid pool token for client {}", id);
+            return;
+        }
+    };
+    let _ = pool.push(stream).await;
 }
 
-async fn read_wrap(s: Arc<TcpStream>) -> Result<Protocol> {
-    read_protocol_timeout(&mut &*s, 10).await
+async fn write_wrap<W>(s: &mut W, version: u8, proto: &Protocol) -> bool
+where
+    W: futures::AsyncWrite + Unpin,
+{
+    write_protocol(s, version, proto).await.is_ok()
 }
@@ -0,0 +1,319 @@
+use crate::protocol::net_proto::Listen;
+use crate::protocol::ClientId;
+use crate::ratelimit::Limiter;
+use crate::ratelimit::Rate;
+use crate::server::vhost::VHostListen;
+use crate::server::vhost::VisitorListen;
+use crate::shutdown::Shutdown;
+use futures::FutureExt;
+use futures_timer::Delay;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+/// Between logs of the usage of each limit
+const REPORT_INTERVAL: Duration = Duration::from_secs(60);
+/// Source IPs tracked at most, new ones are refused while all of them are
+/// throttled
+const MAX_IPS: usize = 4096;
+/// Between scans for source IPs no longer throttled once `MAX_IPS` are
+const PRUNE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Bandwidth limits of visitor sessions, each one shared by all sessions it
+/// applies to
+#[derive(Clone, Default, PartialEq)]
+pub struct RateLimits {
+    /// All visitors of the server
+    pub global: Option<Rate>,
+    /// Visitors of one listener
+    pub listen: Vec<(Listen, Rate)>,
+    /// Visitors of one listener shared by virtual hosts
+    pub vhost: Vec<(VHostListen, Rate)>,
+    /// Visitors of all listeners of one client
+    pub client: Vec<(ClientId, Rate)>,
+}
+
+/// Limits on visitor connections, those over them are dropped at once
+#[derive(Clone, Default, PartialEq)]
+pub struct ConnLimits {
+    /// Sessions at once on one listener
+    pub max_sessions: Vec<(Listen, usize)>,
+    /// Sessions at once on one listener shared by virtual hosts
+    pub vhost_sessions: Vec<(VHostListen, usize)>,
+    /// New sessions per second from one source IP, and the burst
+    pub per_ip: Option<(u32, u32)>,
+    /// Visitors of one client waiting for its worker connection
+    pub max_pending: Option<usize>,
+}
+
+/// The limiters of a running server
+#[derive(Clone, Default)]
+pub(in crate::server) struct Limiters(Arc<Mutex<Running>>);
+
+#[derive(Default)]
+struct Running {
+    global: Option<Arc<Limiter>>,
+    listen: HashMap<VisitorListen, Arc<Limiter>>,
+    client: HashMap<ClientId, Arc<Limiter>>,
+    conns: ConnLimits,
+    max_sessions: HashMap<VisitorListen, usize>,
+    /// Sessions left to each source IP, and when they were counted
+    ips: HashMap<IpAddr, (f64, Instant)>,
+    /// When `ips` was last scanned
+    pruned: Option<Instant>,
+}
+
+/// Counts a session or a pending visitor until dropped
+pub(in crate::server) struct Slot(Arc<AtomicUsize>);
+
+impl Slot {
+    /// Count one more on `count`, unless it reached `max`
+    pub(in crate::server) fn take(count: &Arc<AtomicUsize>, max: usize) -> Option<Slot> {
+        let more = |n| match n < max {
+            true => Some(n + 1),
+            false => None,
+        };
+        let taken = count.fetch_update(Ordering::Relaxed, Ordering::Relaxed, more);
+        taken.ok().map(|_| Slot(count.clone()))
+    }
+
+    /// Count one more on `count`, with no limit
+    pub(in crate::server) fn hold(count: &Arc<AtomicUsize>) -> Slot {
+        count.fetch_add(1, Ordering::Relaxed);
+        Slot(count.clone())
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Limiters {
+    /// Apply `limits` and `conns` to sessions started from now on, limits
+    /// whose rate is unchanged keep their buckets.
+    pub(in crate::server) fn apply(&self, limits: RateLimits, conns: ConnLimits) {
+        let mut running = self.0.lock().unwrap();
+        let max_sessions = conns
+            .max_sessions
+            .iter()
+            .map(|(l, max)| (l.clone().into(), *max));
+        let vhost_sessions = conns.vhost_sessions.iter();
+        let vhost_sessions = vhost_sessions.map(|(l, max)| (l.clone().into(), *max));
+        running.max_sessions = max_sessions.chain(vhost_sessions).collect();
+        if running.conns.per_ip != conns.per_ip {
+            running.ips.clear();
+        }
+        running.conns = conns;
+        let global = running.global.take();
+        running.global = limits
+            .global
+            .map(|rate| keep(global, "global".to_string(), rate));
+        let mut listen = HashMap::new();
+        let listen_rates = limits
+            .listen
+            .into_iter()
+            .map(|(l, rate)| (VisitorListen::from(l), rate));
+        let vhost_rates = limits.vhost.into_iter().map(|(l, rate)| (l.into(), rate));
+        for (l, rate) in listen_rates.chain(vhost_rates) {
+            let name = l.to_string();
+            listen.insert(l.clone(), keep(running.listen.remove(&l), name, rate));
+        }
+        let mut client = HashMap::new();
+        for (id, rate) in limits.client {
+            let name = format!("client {}", id);
+            client.insert(id.clone(), keep(running.client.remove(&id), name, rate));
+        }
+        running.listen = listen;
+        running.client = client;
+    }
+
+    /// The limits of a session of client `id` on listener `listen`
+    pub(in crate::server) fn of(&self, listen: &VisitorListen, id: &ClientId) -> Vec<Arc<Limiter>> {
+        let running = self.0.lock().unwrap();
+        let listen = running.listen.get(listen);
+        let client = running.client.get(id);
+        let limits = running.global.iter().chain(listen).chain(client);
+        limits.cloned().collect()
+    }
+
+    /// Most sessions at once on listener `listen`
+    pub(in crate::server) fn max_sessions(&self, listen: &VisitorListen) -> usize {
+        let running = self.0.lock().unwrap();
+        running
+            .max_sessions
+            .get(listen)
+            .copied()
+            .unwrap_or(usize::MAX)
+    }
+
+    /// Most visitors of a client waiting for its worker connection
+    pub(in crate::server) fn max_pending(&self) -> usize {
+        self.0
+            .lock()
+            .unwrap()
+            .conns
+            .max_pending
+            .unwrap_or(usize::MAX)
+    }
+
+    /// Whether source `ip` may start another session now, counted if so
+    pub(in crate::server) fn admit(&self, ip: IpAddr) -> bool {
+        let mut running = self.0.lock().unwrap();
+        let (rate, burst) = match running.conns.per_ip {
+            Some((rate, burst)) => (rate.max(1) as f64, burst.max(1) as f64),
+            None => return true,
+        };
+        let now = Instant::now();
+        let refill = |(left, since): (f64, Instant)| {
+            (left + now.duration_since(since).as_secs_f64() * rate).min(burst)
+        };
+        if running.ips.len() >= MAX_IPS && !running.ips.contains_key(&ip) {
+            let due = match running.pruned {
+                Some(pruned) => now.duration_since(pruned) >= PRUNE_INTERVAL,
+                None => true,
+            };
+            if due {
+                running.pruned = Some(now);
+                running
+                    .ips
+                    .retain(|_, &mut counted| refill(counted) < burst);
+            }
+            if running.ips.len() >= MAX_IPS {
+                return false;
+            }
+        }
+        let counted = running.ips.get(&ip).copied();
+        let left = counted.map(refill).unwrap_or(burst);
+        if left < 1.0 {
+            return false;
+        }
+        running.ips.insert(ip, (left - 1.0, now));
+        true
+    }
+
+    /// Log the usage of each limit now and then, until `closing`
+    pub(in crate::server) async fn report(self, closing: Shutdown) {
+        let mut last = Instant::now();
+        loop {
+            futures::select! {
+                _ = Delay::new(REPORT_INTERVAL).fuse() => {},
+                _ = closing.wait().fuse() => return,
+            }
+            let elapsed = last.elapsed();
+            last = Instant::now();
+            let running = self.0.lock().unwrap();
+            let limits = running.listen.values().chain(running.client.values());
+            for limit in running.global.iter().chain(limits) {
+                limit.report(elapsed);
+            }
+        }
+    }
+}
+
+/// `old` if it is limited to `rate` already
+fn keep(old: Option<Arc<Limiter>>, name: String, rate: Rate) -> Arc<Limiter> {
+    match old {
+        Some(old) if old.rate() == rate => old,
+        _ => Limiter::new(name, rate),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use std::thread;
+
+    fn per_ip(rate: u32, burst: u32) -> Limiters {
+        let limiters = Limiters::default();
+        let conns = ConnLimits {
+            per_ip: Some((rate, burst)),
+            ..ConnLimits::default()
+        };
+        limiters.apply(RateLimits::default(), conns);
+        limiters
+    }
+
+    fn ip(n: u32) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::from(0x0a00_0000 + n))
+    }
+
+    #[test]
+    fn slots() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let first = Slot::take(&count, 2).unwrap();
+        let second = Slot::take(&count, 2).unwrap();
+        assert!(Slot::take(&count, 2).is_none());
+        drop(first);
+        let third = Slot::take(&count, 2).unwrap();
+        // Held past the limit, but counted
+        let held = Slot::hold(&count);
+        assert_eq!(count.load(Ordering::Relaxed), 3);
+        drop((second, third, held));
+        assert_eq!(count.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn max_pending() {
+        let limiters = Limiters::default();
+        assert_eq!(limiters.max_pending(), usize::MAX);
+        let conns = ConnLimits {
+            max_pending: Some(8),
+            ..ConnLimits::default()
+        };
+        limiters.apply(RateLimits::default(), conns);
+        assert_eq!(limiters.max_pending(), 8);
+    }
+
+    #[test]
+    fn burst_per_ip() {
+        let limiters = per_ip(1, 2);
+        assert!(limiters.admit(ip(1)));
+        assert!(limiters.admit(ip(1)));
+        assert!(!limiters.admit(ip(1)));
+        // Each source has its own burst
+        assert!(limiters.admit(ip(2)));
+        // Not limited unless configured
+        assert!(Limiters::default().admit(ip(1)));
+    }
+
+    #[test]
+    fn refill_per_ip() {
+        let limiters = per_ip(20, 1);
+        assert!(limiters.admit(ip(1)));
+        assert!(!limiters.admit(ip(1)));
+        thread::sleep(Duration::from_millis(60));
+        assert!(limiters.admit(ip(1)));
+        assert!(!limiters.admit(ip(1)));
+    }
+
+    #[test]
+    fn full_table_refuses_new_ips() {
+        let limiters = per_ip(1, 2);
+        for n in 0..MAX_IPS as u32 {
+            assert!(limiters.admit(ip(n)));
+        }
+        assert!(!limiters.admit(ip(MAX_IPS as u32)));
+        // Those tracked still have their burst
+        assert!(limiters.admit(ip(0)));
+        assert_eq!(limiters.0.lock().unwrap().ips.len(), MAX_IPS);
+    }
+
+    #[test]
+    fn full_table_forgets_refilled_ips() {
+        let limiters = per_ip(1000, 1);
+        for n in 0..MAX_IPS as u32 {
+            limiters.admit(ip(n));
+        }
+        thread::sleep(Duration::from_millis(20));
+        assert!(limiters.admit(ip(MAX_IPS as u32)));
+        assert_eq!(limiters.0.lock().unwrap().ips.len(), 1);
+    }
+}
@@ -0,0 +1,134 @@
+use crate::error::Error;
+use crate::protocol::net_proto::Listen;
+use crate::protocol::ClientId;
+use crate::server::VHostListen;
+use log::warn;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::Mutex;
+use thiserror::Error;
+
+/// Identifies a listener of the server
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ListenerId {
+    /// Accepts control and worker connections of clients
+    Client(SocketAddr),
+    /// Accepts visitors of a client
+    Visitor(Listen, ClientId),
+    /// Accepts visitors of several clients, routed by host
+    VHost(VHostListen),
+}
+
+impl fmt::Display for ListenerId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ListenerId::Client(socket) => write!(f, "client listen {}", socket),
+            ListenerId::Visitor(Listen::Tcp(socket), id) => {
+                write!(f, "tcp listen {} of {}", socket, id)
+            }
+            ListenerId::Visitor(Listen::Udp(socket), id) => {
+                write!(f, "udp listen {} of {}", socket, id)
+            }
+            ListenerId::VHost(listen) => write!(f, "{} vhost listen", listen),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListenState {
+    Up,
+    Failed(String),
+    /// Stopped by the admin until enabled again
+    Disabled,
+}
+
+/// A listener which failed to bind or stopped serving
+#[derive(Debug, Error)]
+#[error("{listener}: {error}")]
+pub struct ListenError {
+    pub listener: ListenerId,
+    #[source]
+    pub error: Error,
+}
+
+/// State of each listener, shared with the running server
+#[derive(Clone, Default)]
+pub struct Listeners {
+    state: Arc<Mutex<HashMap<ListenerId, ListenState>>>,
+}
+
+impl Listeners {
+    /// Listeners currently serving
+    pub fn up(&self) -> Vec<ListenerId> {
+        let state = self.state.lock().unwrap();
+        let up = state.iter().filter(|(_, s)| **s == ListenState::Up);
+        up.map(|(id, _)| id.clone()).collect()
+    }
+
+    pub fn state(&self) -> Vec<(ListenerId, ListenState)> {
+        let state = self.state.lock().unwrap();
+        state
+            .iter()
+            .map(|(id, s)| (id.clone(), s.clone()))
+            .collect()
+    }
+
+    pub(in crate::server) fn is_up(&self, id: &ListenerId) -> bool {
+        let state = self.state.lock().unwrap();
+        state.get(id) == Some(&ListenState::Up)
+    }
+
+    pub(in crate::server) fn set_up(&self, id: &ListenerId) {
+        self.set(id, ListenState::Up);
+    }
+
+    pub(in crate::server) fn set(&self, id: &ListenerId, s: ListenState) {
+        let mut state = self.state.lock().unwrap();
+        state.insert(id.clone(), s);
+    }
+
+    /// Record how listener `id` ended
+    pub(in crate::server) fn done(
+        &self,
+        id: ListenerId,
+        r: Result<(), Error>,
+    ) -> Result<(), ListenError> {
+        let mut state = self.state.lock().unwrap();
+        match r {
+            Ok(()) => {
+                state.remove(&id);
+                Ok(())
+            }
+            Err(error) => {
+                warn!(target: "shadow-peer", "{}: {}", id, error);
+                state.insert(id.clone(), ListenState::Failed(error.to_string()));
+                Err(ListenError {
+                    listener: id,
+                    error,
+                })
+            }
+        }
+    }
+
+    /// Record listener `id` as up, until the returned guard is dropped
+    pub(in crate::server) fn hold(&self, id: ListenerId) -> Hold {
+        self.set_up(&id);
+        Hold {
+            listeners: self.clone(),
+            id,
+        }
+    }
+}
+
+pub(in crate::server) struct Hold {
+    listeners: Listeners,
+    id: ListenerId,
+}
+
+impl Drop for Hold {
+    fn drop(&mut self) {
+        self.listeners.state.lock().unwrap().remove(&self.id);
+    }
+}
@@ -1,80 +1,569 @@
+pub use self::access::Access;
+pub use self::access::AccessLists;
+pub use self::access::BadCidr;
+pub use self::access::Cidr;
+use self::access::Gate;
+use self::admin::Admin;
+use self::admin::Command;
 pub use self::client::CliListen;
+use self::client::StreamShare;
+pub use self::limits::ConnLimits;
+use self::limits::Limiters;
+pub use self::limits::RateLimits;
+pub use self::listeners::ListenError;
+pub use self::listeners::ListenState;
+pub use self::listeners::ListenerId;
+pub use self::listeners::Listeners;
+use self::pool::Pool;
+pub use self::register::Policy;
+use self::register::Registered;
+use self::reload::Running;
+pub use self::reload::Settings;
 use self::reqmap::ReqMapMessage;
-use crate::error::err_exit;
+use self::sessions::Sessions;
+pub use self::vhost::VHost;
+pub use self::vhost::VHostListen;
+use crate::heartbeat::Heartbeat;
+use crate::metrics;
+use crate::metrics::Metrics;
+use crate::mux::Session;
 use crate::protocol::net_proto::Establish;
 pub use crate::protocol::net_proto::Listen;
 pub use crate::protocol::ClientId;
 use crate::protocol::Protocol;
+pub use crate::ratelimit::BadRate;
+pub use crate::ratelimit::Rate;
+pub use crate::reload::Reload;
+pub use crate::shutdown::Shutdown;
+use crate::shutdown::DRAIN_TMOUT;
+pub use crate::timeouts::BadTimeouts;
+pub use crate::timeouts::TimeoutSecs;
+pub use crate::timeouts::Timeouts;
+use async_std::net::TcpListener;
 use async_std::sync::Arc;
 use async_std::sync::RwLock;
 use async_std::task;
 use futures::channel::mpsc;
+use futures::channel::mpsc::UnboundedReceiver;
 use futures::channel::mpsc::UnboundedSender;
+use futures::FutureExt;
+use futures::StreamExt;
+use log::warn;
 use std::collections::HashMap;
-use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::result::Result as StdResult;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
 
+mod access;
+mod admin;
 mod client;
+mod limits;
+mod listeners;
+mod pool;
+mod register;
+mod reload;
 mod reqmap;
+mod sessions;
+mod vhost;
 mod visitor;
 
-pub(self) type ClientMap = Arc<RwLock<HashMap<ClientId, Client>>>;
+type ClientMap = Arc<RwLock<HashMap<ClientId, Instances>>>;
 type ReqMapSender = UnboundedSender<(Establish, ReqMapMessage)>;
 
 pub struct Server {
     cli_listen: Vec<CliListen>,
     client: ClientMap,
-    listen: HashMap<Listen, ClientId>,
-    valid_client: Arc<HashSet<ClientId>>,
+    settings: Settings,
+    listeners: Listeners,
+    shutdown: Shutdown,
+    drain: Duration,
+    reload: UnboundedSender<Settings>,
+    reloaded: UnboundedReceiver<Settings>,
+    admin: Option<SocketAddr>,
+    admin_token: Option<String>,
+    metrics: Option<SocketAddr>,
+    timeouts: Timeouts,
+    balance: Balance,
+}
+
+/// How visitors of a client logged in more than once are spread among its
+/// connections
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Balance {
+    /// Each connection in turn
+    RoundRobin,
+    /// The connection serving the fewest sessions
+    LeastConnections,
 }
 
 impl Server {
-    pub fn new(listen: Vec<(Listen, ClientId)>, cli_listen: Vec<CliListen>) -> Server {
-        let valid_client = Arc::new(listen.iter().map(|(_, id)| id.clone()).collect());
-        let listen = listen.into_iter().collect();
+    /// `secret` holds the shared secret of each client, clients which own no
+    /// listen and may register none are never accepted.
+    pub fn new(
+        listen: Vec<(Listen, ClientId)>,
+        cli_listen: Vec<CliListen>,
+        secret: Vec<(ClientId, String)>,
+    ) -> Server {
+        let (reload, reloaded) = mpsc::unbounded();
         Server {
             cli_listen,
             client: Arc::new(RwLock::new(HashMap::new())),
-            listen,
-            valid_client,
+            settings: Settings {
+                listen,
+                secret,
+                policy: vec![],
+                rate_limits: RateLimits::default(),
+                conn_limits: ConnLimits::default(),
+                access: AccessLists::default(),
+                proxied: vec![],
+                vhosts: vec![],
+            },
+            listeners: Listeners::default(),
+            shutdown: Shutdown::new(),
+            drain: Duration::from_secs(DRAIN_TMOUT),
+            reload,
+            reloaded,
+            admin: None,
+            admin_token: None,
+            metrics: None,
+            timeouts: Timeouts::default(),
+            balance: Balance::RoundRobin,
         }
     }
 
-    pub async fn run(self) {
-        let mut join = vec![];
+    /// Let clients register their own listeners, within their `Policy`
+    pub fn policy(mut self, policy: Vec<(ClientId, Policy)>) -> Server {
+        self.settings.policy = policy;
+        self
+    }
+
+    /// Limit the bandwidth of visitors
+    pub fn rate_limits(mut self, limits: RateLimits) -> Server {
+        self.settings.rate_limits = limits;
+        self
+    }
+
+    /// Limit the visitor connections accepted
+    pub fn conn_limits(mut self, limits: ConnLimits) -> Server {
+        self.settings.conn_limits = limits;
+        self
+    }
+
+    /// Let visitors in by their address
+    pub fn access(mut self, lists: AccessLists) -> Server {
+        self.settings.access = lists;
+        self
+    }
+
+    /// Visitor listeners behind a load balancer, whose connections start
+    /// with a PROXY v1 or v2 header
+    pub fn proxied(mut self, listen: Vec<Listen>) -> Server {
+        self.settings.proxied = listen;
+        self
+    }
+
+    /// Listeners shared by clients, each visitor is routed by the host it
+    /// asks for
+    pub fn vhosts(mut self, vhosts: Vec<(VHostListen, VHost)>) -> Server {
+        self.settings.vhosts = vhosts;
+        self
+    }
+
+    /// State of the listeners, kept up to date once running
+    pub fn listeners(&self) -> Listeners {
+        self.listeners.clone()
+    }
+
+    /// Handle to stop the server once running
+    pub fn shutdown_handle(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+
+    /// Handle to change the `Settings` once running, the client listeners
+    /// stay as they are.
+    pub fn reload_handle(&self) -> Reload<Settings> {
+        Reload::new(self.reload.clone())
+    }
+
+    /// Serve the admin API on `listen`, it is not served off loopback
+    /// without an `admin_token`
+    pub fn admin(mut self, listen: SocketAddr) -> Server {
+        self.admin = Some(listen);
+        self
+    }
+
+    /// Bearer token required by the admin API
+    pub fn admin_token(mut self, token: String) -> Server {
+        self.admin_token = Some(token);
+        self
+    }
+
+    /// Export metrics for Prometheus on `listen`
+    pub fn metrics(mut self, listen: SocketAddr) -> Server {
+        self.metrics = Some(listen);
+        self
+    }
+
+    /// Time given to visitor sessions once shut down
+    pub fn drain(mut self, deadline: Duration) -> Server {
+        self.drain = deadline;
+        self
+    }
+
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Server {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Spread the visitors of a client logged in more than once by `balance`
+    pub fn balance(mut self, balance: Balance) -> Server {
+        self.balance = balance;
+        self
+    }
+
+    /// Serve until shut down or every listener is gone. A listener which
+    /// fails is logged and returned, the others keep running.
+    pub async fn run(mut self) -> StdResult<(), Vec<ListenError>> {
         let (send, recv) = mpsc::unbounded();
         task::spawn(reqmap::actor(recv));
+        // Control connections are closed once the visitors are drained
+        let closing = Shutdown::new();
+        let share = client::StreamShare {
+            cli: self.client.clone(),
+            req: send,
+            listeners: self.listeners.clone(),
+            policy: Default::default(),
+            secret: Default::default(),
+            sessions: Sessions::default(),
+            metrics: Metrics::default(),
+            limiters: Limiters::default(),
+            gate: Gate::default(),
+            proxied: Default::default(),
+            vhosts: Default::default(),
+            timeouts: self.timeouts,
+            balance: self.balance,
+            shutdown: self.shutdown.clone(),
+            closing: closing.clone(),
+        };
+        let (fail, mut failed) = mpsc::unbounded();
+        let mut running = Running::new(share.clone(), fail.clone());
+        running.apply(self.settings).await;
+
         // Clients Listen
+        let mut join = vec![];
         for listen in self.cli_listen {
-            let cli = self.client.clone();
-            let send = send.clone();
-            let idset = self.valid_client.clone();
-            let task = async move {
-                match listen {
-                    CliListen::Tcp(socket) => client::tcp(socket, cli, send, idset).await,
+            let (socket, tls) = match listen {
+                CliListen::Tcp(socket) => (socket, None),
+                CliListen::Tls(socket, tls) => (socket, Some(tls)),
+            };
+            let id = ListenerId::Client(socket);
+            let listeners = self.listeners.clone();
+            let tcp = match TcpListener::bind(socket).await {
+                Ok(tcp) => tcp,
+                Err(e) => {
+                    if let Err(e) = listeners.done(id, Err(e.into())) {
+                        let _ = fail.unbounded_send(e);
+                    }
+                    continue;
                 }
-                .unwrap_or_else(|e| err_exit(1, e));
             };
-            join.push(task::spawn(task));
+            listeners.set_up(&id);
+            let (share, fail) = (share.clone(), fail.clone());
+            join.push(task::spawn(async move {
+                let r = client::tcp(tcp, tls, share).await;
+                if let Err(e) = listeners.done(id, r) {
+                    let _ = fail.unbounded_send(e);
+                }
+            }));
         }
 
-        // Visitors Listen
-        for (listen, id) in self.listen {
-            let climap = self.client.clone();
-            let send = send.clone();
-            let task = async move {
-                match listen {
-                    Listen::Tcp(socket) => visitor::tcp(socket, id, climap, send).await,
+        let limiters = share.limiters.clone();
+        join.push(task::spawn(limiters.report(closing.clone())));
+
+        let (command, mut commands) = mpsc::unbounded();
+        match self.admin {
+            Some(listen) if !listen.ip().is_loopback() && self.admin_token.is_none() => {
+                warn!(target: "shadow-peer", "Admin on {} needs a token, not served", listen)
+            }
+            Some(listen) => match TcpListener::bind(listen).await {
+                Ok(tcp) => {
+                    let admin = Admin {
+                        share: share.clone(),
+                        command,
+                        token: self.admin_token.clone(),
+                    };
+                    join.push(task::spawn(admin::serve(tcp, admin)));
                 }
-                .unwrap_or_else(|e| err_exit(2, e));
-            };
-            join.push(task::spawn(task));
+                Err(e) => warn!(target: "shadow-peer", "Admin listen {}: {}", listen, e),
+            },
+            None => {}
+        }
+        if let Some(listen) = self.metrics {
+            match TcpListener::bind(listen).await {
+                Ok(tcp) => {
+                    let (metrics, closing) = (share.metrics.clone(), closing.clone());
+                    join.push(task::spawn(metrics::serve(tcp, metrics, closing)));
+                }
+                Err(e) => warn!(target: "shadow-peer", "Metrics listen {}: {}", listen, e),
+            }
+        }
+
+        let mut errors = vec![];
+        let shutdown = self.shutdown.clone();
+        let mut stop = Box::pin(shutdown.wait().fuse());
+        let stopped = loop {
+            while let Ok(Some(e)) = failed.try_next() {
+                errors.push(e);
+            }
+            if self.listeners.up().is_empty() {
+                break false;
+            }
+            futures::select! {
+                e = failed.next() => errors.extend(e),
+                settings = self.reloaded.next() => {
+                    if let Some(settings) = settings {
+                        running.apply(settings).await;
+                    }
+                },
+                command = commands.next() => match command {
+                    Some(Command::Enable(listen, enable, done)) => {
+                        let _ = done.send(running.enable(&listen, enable).await);
+                    }
+                    None => {}
+                },
+                _ = stop => break true,
+            }
+        };
+        if stopped {
+            for client in self.client.read().await.values().flat_map(|i| &i.clients) {
+                let _ = client.estab_sender.unbounded_send(Protocol::Goodbye);
+            }
+            let left = shutdown.drain(self.drain).await;
+            if left > 0 {
+                warn!(target: "shadow-peer", "Shutdown with {} sessions left", left);
+            }
+        }
+        closing.shutdown();
+        running.close().await;
+        futures::future::join_all(join).await;
+        while let Ok(Some(e)) = failed.try_next() {
+            errors.push(e);
+        }
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(errors),
+        }
+    }
+}
+
+/// Disconnect every connection of client `id`, return whether it was
+/// connected
+async fn kick(share: &StreamShare, id: &ClientId) -> bool {
+    let instances = {
+        let mut cli = share.cli.write().await;
+        let instances = cli.remove(id);
+        count_clients(&share.metrics, &cli);
+        instances
+    };
+    match instances {
+        Some(instances) => {
+            for client in &instances.clients {
+                client.kick.shutdown();
+            }
+            instances.close().await;
+            true
+        }
+        None => false,
+    }
+}
+
+/// Update the clients gauge once `cli` changed
+fn count_clients(metrics: &Metrics, cli: &HashMap<ClientId, Instances>) {
+    let connected: usize = cli.values().map(|i| i.clients.len()).sum();
+    metrics.gauge(&metrics::CLIENTS, &[]).set(connected as f64);
+}
+
+/// The connections of one client, each logged in on its own
+struct Instances {
+    clients: Vec<Client>,
+    balance: Balance,
+    /// Turn of the next visitor
+    turn: AtomicUsize,
+    /// Listeners registered by the connections
+    listeners: Vec<Registered>,
+    /// Visitors waiting for a worker connection
+    pending: Arc<AtomicUsize>,
+}
+
+impl Instances {
+    fn new(balance: Balance) -> Instances {
+        Instances {
+            clients: vec![],
+            balance,
+            turn: AtomicUsize::new(0),
+            listeners: vec![],
+            pending: Default::default(),
+        }
+    }
+
+    /// Stop the listeners registered by the client
+    async fn close(self) {
+        for listener in self.listeners {
+            listener.close().await;
+        }
+    }
+
+    /// The connection the next visitor is sent to, of those still open
+    fn pick(&self) -> Option<&Client> {
+        let len = self.clients.len();
+        let turn = self.turn.fetch_add(1, Ordering::Relaxed);
+        let clients = (0..len).map(|i| &self.clients[(turn + i) % len]);
+        let mut open = clients.filter(|c| !c.estab_sender.is_closed());
+        match self.balance {
+            Balance::RoundRobin => open.next(),
+            // Ties go in turn
+            Balance::LeastConnections => open.min_by_key(|c| c.active.load(Ordering::Relaxed)),
         }
-        for handle in join {
-            handle.await;
+    }
+
+    /// Forget the connection logged in with `pool`, return the listeners no
+    /// other connection asks for, to be stopped
+    fn forget(&mut self, pool: &Arc<Pool>) -> Vec<Registered> {
+        self.clients.retain(|c| !Arc::ptr_eq(&c.pool, pool));
+        let mut released = register::release(&mut self.listeners, pool);
+        if self.clients.is_empty() {
+            released.append(&mut self.listeners);
         }
+        released
+    }
+
+    /// The connection logged in with `pool`
+    fn get_mut(&mut self, pool: &Arc<Pool>) -> Option<&mut Client> {
+        let mut clients = self.clients.iter_mut();
+        clients.find(|c| Arc::ptr_eq(&c.pool, pool))
     }
 }
 
-pub(self) struct Client {
+struct Client {
     estab_sender: UnboundedSender<Protocol>,
+    /// Visitor streams are multiplexed over the control connection
+    mux: Option<Arc<Session>>,
+    pool: Arc<Pool>,
+    peer: SocketAddr,
+    since: Instant,
+    /// Pings of the control connection
+    heartbeat: Arc<Mutex<Heartbeat>>,
+    /// Visitor sessions served
+    active: Arc<AtomicUsize>,
+    /// Closes the control connection
+    kick: Shutdown,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::net_proto::Proto;
+    use crate::protocol::net_proto::Remote;
+
+    /// Add a connection to `instances`, open while the receiver is kept
+    fn connect(instances: &mut Instances) -> (Arc<Pool>, UnboundedReceiver<Protocol>) {
+        let (send, recv) = mpsc::unbounded();
+        let pool = Arc::new(Pool::new());
+        instances.clients.push(Client {
+            estab_sender: send,
+            mux: None,
+            pool: pool.clone(),
+            peer: "192.0.2.1:5000".parse().unwrap(),
+            since: Instant::now(),
+            heartbeat: Arc::new(Mutex::new(Heartbeat::new(3))),
+            active: Default::default(),
+            kick: Shutdown::new(),
+        });
+        (pool, recv)
+    }
+
+    /// Index in `pools` of the connection picked next
+    fn pick(instances: &Instances, pools: &[&Arc<Pool>]) -> Option<usize> {
+        let client = instances.pick()?;
+        pools
+            .iter()
+            .position(|pool| Arc::ptr_eq(&client.pool, pool))
+    }
+
+    fn listener(port: u16, owners: &[&Arc<Pool>]) -> Registered {
+        let remote = Remote {
+            proto: Proto::Tcp,
+            port,
+            bind: None,
+        };
+        Registered::idle(remote, owners.iter().map(|&p| p.clone()).collect())
+    }
+
+    #[test]
+    fn round_robin() {
+        assert!(Instances::new(Balance::RoundRobin).pick().is_none());
+        let mut instances = Instances::new(Balance::RoundRobin);
+        let (a, _a) = connect(&mut instances);
+        let (b, b_recv) = connect(&mut instances);
+        let (c, _c) = connect(&mut instances);
+        let pools = [&a, &b, &c];
+        let picked: Vec<_> = (0..6).map(|_| pick(&instances, &pools)).collect();
+        let turns = [0, 1, 2, 0, 1, 2].iter().map(|&i| Some(i));
+        assert_eq!(picked, turns.collect::<Vec<_>>());
+        // A closed connection is skipped, the next open one takes its turn
+        drop(b_recv);
+        let picked: Vec<_> = (0..4).map(|_| pick(&instances, &pools)).collect();
+        assert!(picked.iter().all(|i| *i != Some(1)));
+        assert!(picked.contains(&Some(0)) && picked.contains(&Some(2)));
+    }
+
+    #[test]
+    fn least_connections() {
+        let mut instances = Instances::new(Balance::LeastConnections);
+        let (a, _a) = connect(&mut instances);
+        let (b, _b) = connect(&mut instances);
+        let (c, c_recv) = connect(&mut instances);
+        let pools = [&a, &b, &c];
+        // Ties go in turn
+        let picked: Vec<_> = (0..3).map(|_| pick(&instances, &pools)).collect();
+        assert_eq!(picked, vec![Some(0), Some(1), Some(2)]);
+        for (client, active) in instances.clients.iter().zip(&[2, 1, 0]) {
+            client.active.store(*active, Ordering::Relaxed);
+        }
+        assert_eq!(pick(&instances, &pools), Some(2));
+        assert_eq!(pick(&instances, &pools), Some(2));
+        drop(c_recv);
+        assert_eq!(pick(&instances, &pools), Some(1));
+    }
+
+    #[test]
+    fn forget_one_connection() {
+        let mut instances = Instances::new(Balance::RoundRobin);
+        let (a, _a) = connect(&mut instances);
+        let (b, _b) = connect(&mut instances);
+        instances.listeners = vec![
+            listener(8000, &[&a, &b]),
+            listener(8001, &[&a]),
+            listener(8002, &[&b]),
+        ];
+        let released = instances.forget(&a);
+        let ports: Vec<_> = released.iter().map(|l| l.port()).collect();
+        assert_eq!(ports, vec![8001]);
+        assert_eq!(instances.clients.len(), 1);
+        assert!(Arc::ptr_eq(&instances.clients[0].pool, &b));
+        let ports: Vec<_> = instances.listeners.iter().map(|l| l.port()).collect();
+        assert_eq!(ports, vec![8000, 8002]);
+        assert!(instances.listeners.iter().all(|l| l.owned_by(&b)));
+        assert!(!instances.listeners.iter().any(|l| l.owned_by(&a)));
+        assert_eq!(pick(&instances, &[&a, &b]), Some(1));
+
+        // The last connection takes every listener along
+        let released = instances.forget(&b);
+        assert_eq!(released.len(), 2);
+        assert!(instances.clients.is_empty() && instances.listeners.is_empty());
+    }
 }
@@ -0,0 +1,68 @@
+use crate::protocol::auth;
+use crate::protocol::auth::Token;
+use crate::protocol::PARK_IDLE;
+use crate::stream::BoxStream;
+use async_std::sync::Mutex;
+use futures::AsyncReadExt;
+use futures::FutureExt;
+use std::mem;
+use std::time::Duration;
+use std::time::Instant;
+
+/// Most idle worker connections a client could park, told in `Hello`
+pub(in crate::server) const POOL_MAX: usize = 64;
+/// Longest a connection is used after it was parked, well before the client
+/// gives it up
+const PARK_FRESH: Duration = Duration::from_secs(PARK_IDLE.as_secs() - 10);
+
+/// Idle worker connections parked by a client, a parked connection must
+/// present the token granted over the control connection.
+pub struct Pool {
+    pub token: Token,
+    streams: Mutex<Vec<(BoxStream, Instant)>>,
+}
+
+impl Pool {
+    pub fn new() -> Pool {
+        Pool {
+            token: auth::token(),
+            streams: Mutex::new(vec![]),
+        }
+    }
+
+    /// Park `stream`, give it back if the pool is full of live connections.
+    pub async fn push(&self, stream: BoxStream) -> Option<BoxStream> {
+        let mut streams = self.streams.lock().await;
+        if streams.len() >= POOL_MAX {
+            for (mut stream, since) in mem::take(&mut *streams) {
+                if usable(&mut stream, since) {
+                    streams.push((stream, since));
+                }
+            }
+        }
+        if streams.len() >= POOL_MAX {
+            return Some(stream);
+        }
+        streams.push((stream, Instant::now()));
+        None
+    }
+
+    /// The latest parked connection still usable, the others popped on the
+    /// way are closed.
+    pub async fn pop(&self) -> Option<BoxStream> {
+        let mut streams = self.streams.lock().await;
+        while let Some((mut stream, since)) = streams.pop() {
+            if usable(&mut stream, since) {
+                return Some(stream);
+            }
+        }
+        None
+    }
+}
+
+/// Nothing is sent on a parked connection but the use of it, anything to
+/// read is its end.
+fn usable(stream: &mut BoxStream, since: Instant) -> bool {
+    let mut buf = [0u8; 1];
+    since.elapsed() < PARK_FRESH && stream.read(&mut buf).now_or_never().is_none()
+}
@@ -0,0 +1,163 @@
+use super::client::StreamShare;
+use super::visitor::Bound;
+use super::ListenerId;
+use super::Pool;
+use crate::protocol::net_proto::Remote;
+use crate::protocol::ClientId;
+use async_std::sync::Arc;
+use async_std::task;
+use async_std::task::JoinHandle;
+use log::warn;
+use std::mem;
+use std::net::SocketAddr;
+use std::ops::RangeInclusive;
+use std::result::Result as StdResult;
+
+/// Listeners a client may register for itself
+#[derive(Clone, Default, PartialEq)]
+pub struct Policy {
+    /// Ports allowed for both TCP and UDP
+    pub ports: Vec<RangeInclusive<u16>>,
+    /// Most listeners held at once, by all connections of the client
+    pub max_listen: usize,
+}
+
+impl Policy {
+    fn allow(&self, remote: &Remote) -> bool {
+        remote.port != 0 && self.ports.iter().any(|r| r.contains(&remote.port))
+    }
+}
+
+/// A listener registered by a client, open while any of its connections asks
+/// for it
+pub(in crate::server) struct Registered {
+    remote: Remote,
+    addr: SocketAddr,
+    task: JoinHandle<()>,
+    /// Pools of the connections asking for it
+    owners: Vec<Arc<Pool>>,
+}
+
+impl Registered {
+    pub(in crate::server) async fn close(self) {
+        self.task.cancel().await;
+    }
+
+    /// Whether the connection logged in with `pool` asks for it
+    pub(in crate::server) fn owned_by(&self, pool: &Arc<Pool>) -> bool {
+        self.owners.iter().any(|owner| Arc::ptr_eq(owner, pool))
+    }
+}
+
+#[cfg(test)]
+impl Registered {
+    /// A listener of `remote` asked for by `owners`, not accepting anything
+    pub(in crate::server) fn idle(remote: Remote, owners: Vec<Arc<Pool>>) -> Registered {
+        Registered {
+            addr: SocketAddr::from(([127, 0, 0, 1], remote.port)),
+            remote,
+            task: task::spawn(async {}),
+            owners,
+        }
+    }
+
+    pub(in crate::server) fn port(&self) -> u16 {
+        self.remote.port
+    }
+}
+
+/// Open the listeners requested by the connection of client `id` logged in
+/// with `pool`. `held` are those of all connections of the client, they are
+/// shared by those asking for the same. Those the connection asks no more are
+/// stopped unless others ask for them. Return the result of each request.
+pub(in crate::server) async fn register(
+    remotes: Vec<Remote>,
+    id: &ClientId,
+    share: &StreamShare,
+    pool: &Arc<Pool>,
+    held: &mut Vec<Registered>,
+) -> Vec<StdResult<SocketAddr, String>> {
+    let policy = share.policy.read().await.get(id).cloned();
+    for listener in held.iter_mut() {
+        if !remotes.contains(&listener.remote) {
+            listener.owners.retain(|owner| !Arc::ptr_eq(owner, pool));
+        }
+    }
+    for listener in orphans(held) {
+        listener.close().await;
+    }
+    let mut result = vec![];
+    for remote in remotes {
+        let at = held.iter().position(|r| r.remote == remote);
+        let r = match (at, &policy) {
+            (Some(at), _) => {
+                let listener = &mut held[at];
+                if !listener.owned_by(pool) {
+                    listener.owners.push(pool.clone());
+                }
+                Ok(listener.addr)
+            }
+            (None, Some(policy)) if held.len() >= policy.max_listen => {
+                Err("Too many listeners".to_string())
+            }
+            (None, Some(policy)) if policy.allow(&remote) => {
+                bind(remote, id, share, pool).await.map(|listener| {
+                    let addr = listener.addr;
+                    held.push(listener);
+                    addr
+                })
+            }
+            _ => Err(format!("{:?} {} not allowed", remote.proto, remote.port)),
+        };
+        if let Err(e) = &r {
+            warn!(target: "shadow-peer", "Client {} register failed: {}", id, e);
+        }
+        result.push(r);
+    }
+    result
+}
+
+/// Forget the connection logged in with `pool`, return the listeners of
+/// `held` no other connection asks for, to be stopped
+pub(in crate::server) fn release(held: &mut Vec<Registered>, pool: &Arc<Pool>) -> Vec<Registered> {
+    for listener in held.iter_mut() {
+        listener.owners.retain(|owner| !Arc::ptr_eq(owner, pool));
+    }
+    orphans(held)
+}
+
+/// Take the listeners of `held` no connection asks for
+fn orphans(held: &mut Vec<Registered>) -> Vec<Registered> {
+    let (orphans, owned) = mem::take(held)
+        .into_iter()
+        .partition(|r| r.owners.is_empty());
+    *held = owned;
+    orphans
+}
+
+async fn bind(
+    remote: Remote,
+    id: &ClientId,
+    share: &StreamShare,
+    pool: &Arc<Pool>,
+) -> StdResult<Registered, String> {
+    let listen = remote.listen();
+    let bound = Bound::bind(&listen).await.map_err(|e| e.to_string())?;
+    let addr = bound.local_addr().map_err(|e| e.to_string())?;
+    let hold = share
+        .listeners
+        .hold(ListenerId::Visitor(listen, id.clone()));
+    let (id, share) = (id.clone(), share.clone());
+    let task = task::spawn(async move {
+        let _hold = hold;
+        if let Err(e) = bound.serve(id, &share).await {
+            warn!(target: "shadow-peer", "{}", e);
+        }
+    });
+    Ok(Registered {
+        remote,
+        addr,
+        task,
+        owners: vec![pool.clone()],
+    })
+}
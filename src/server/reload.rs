@@ -0,0 +1,249 @@
+use super::access::AccessLists;
+use super::client::StreamShare;
+use super::kick;
+use super::limits::ConnLimits;
+use super::limits::RateLimits;
+use super::vhost::VHost;
+use super::vhost::VHostListen;
+use super::visitor;
+use super::visitor::Bound;
+use super::ListenError;
+use super::ListenState;
+use super::ListenerId;
+use super::Policy;
+use crate::protocol::net_proto::Listen;
+use crate::protocol::ClientId;
+use async_std::net::TcpListener;
+use async_std::task;
+use async_std::task::JoinHandle;
+use futures::channel::mpsc::UnboundedSender;
+use log::warn;
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+/// Settings of a server which may change while it runs
+#[derive(Default)]
+pub struct Settings {
+    pub listen: Vec<(Listen, ClientId)>,
+    /// Shared secret of each client, clients which own no listen and may
+    /// register none are never accepted.
+    pub secret: Vec<(ClientId, String)>,
+    pub policy: Vec<(ClientId, Policy)>,
+    pub rate_limits: RateLimits,
+    pub conn_limits: ConnLimits,
+    pub access: AccessLists,
+    /// Visitor listeners behind a load balancer, whose connections start
+    /// with a PROXY v1 or v2 header
+    pub proxied: Vec<Listen>,
+    /// Listeners shared by clients, routing visitors by host
+    pub vhosts: Vec<(VHostListen, VHost)>,
+}
+
+/// The visitor listeners and the client auth of a running server
+pub(in crate::server) struct Running {
+    share: StreamShare,
+    /// Listeners which fail are reported here
+    failed: UnboundedSender<ListenError>,
+    visitors: HashMap<Listen, (ClientId, Option<JoinHandle<()>>)>,
+    /// Kept down across reloads, until enabled again
+    disabled: HashSet<Listen>,
+    /// Shared by clients, restarted only if they failed
+    vhosts: HashMap<VHostListen, Option<JoinHandle<()>>>,
+}
+
+impl Running {
+    pub(in crate::server) fn new(
+        share: StreamShare,
+        failed: UnboundedSender<ListenError>,
+    ) -> Running {
+        Running {
+            share,
+            failed,
+            visitors: HashMap::new(),
+            disabled: HashSet::new(),
+            vhosts: HashMap::new(),
+        }
+    }
+
+    /// Apply `settings`, clients whose secret or policy changed are kicked,
+    /// listeners which changed or failed are started again. Others are left
+    /// alone, limits apply to the sessions started from now on.
+    pub(in crate::server) async fn apply(&mut self, settings: Settings) {
+        self.share
+            .limiters
+            .apply(settings.rate_limits, settings.conn_limits);
+        self.share.gate.apply(settings.access);
+        *self.share.proxied.write().await = settings.proxied.into_iter().collect();
+        let listen: HashMap<_, _> = settings.listen.into_iter().collect();
+        let policy: HashMap<_, _> = settings.policy.into_iter().collect();
+        let mut vhosts: HashMap<_, Vec<_>> = HashMap::new();
+        for (l, vhost) in settings.vhosts {
+            vhosts.entry(l).or_default().push(vhost);
+        }
+        let mut secret: HashMap<_, _> = settings.secret.into_iter().collect();
+        secret.retain(|id, _| {
+            let vhost = vhosts.values().flatten().any(|v| v.client == *id);
+            listen.values().any(|cid| cid == id) || policy.contains_key(id) || vhost
+        });
+
+        let changed: HashSet<ClientId> = {
+            let old_secret = self.share.secret.read().await;
+            let old_policy = self.share.policy.read().await;
+            old_secret
+                .keys()
+                .chain(secret.keys())
+                .filter(|&id| {
+                    old_secret.get(id) != secret.get(id) || old_policy.get(id) != policy.get(id)
+                })
+                .cloned()
+                .collect()
+        };
+        *self.share.secret.write().await = secret;
+        *self.share.policy.write().await = policy;
+        for id in changed {
+            if kick(&self.share, &id).await {
+                warn!(target: "shadow-peer", "Client {} kicked, its auth changed", id);
+            }
+        }
+
+        self.disabled.retain(|l| listen.contains_key(l));
+        let stale: Vec<_> = self
+            .visitors
+            .iter()
+            .filter(|(l, (cid, _))| {
+                let id = ListenerId::Visitor((*l).clone(), cid.clone());
+                let down = !self.disabled.contains(l) && !self.share.listeners.is_up(&id);
+                listen.get(l) != Some(cid) || down
+            })
+            .map(|(l, _)| l.clone())
+            .collect();
+        for l in stale {
+            self.stop(&l).await;
+        }
+        for (l, cid) in listen {
+            if !self.visitors.contains_key(&l) {
+                self.start(l, cid).await;
+            }
+        }
+
+        let stale: Vec<_> = self
+            .vhosts
+            .keys()
+            .filter(|l| {
+                let up = self.share.listeners.is_up(&ListenerId::VHost((*l).clone()));
+                !vhosts.contains_key(l) || !up
+            })
+            .cloned()
+            .collect();
+        for l in stale {
+            self.stop_vhost(&l).await;
+        }
+        let fresh: Vec<_> = vhosts.keys().cloned().collect();
+        *self.share.vhosts.write().await = vhosts;
+        for l in fresh {
+            if !self.vhosts.contains_key(&l) {
+                self.start_vhost(l).await;
+            }
+        }
+    }
+
+    /// Start or stop listener `listen`, return false if it is not configured
+    pub(in crate::server) async fn enable(&mut self, listen: &Listen, enable: bool) -> bool {
+        let cid = match self.visitors.get(listen) {
+            Some((cid, _)) => cid.clone(),
+            None => return false,
+        };
+        let changed = match enable {
+            true => self.disabled.remove(listen),
+            false => self.disabled.insert(listen.clone()),
+        };
+        if changed {
+            self.stop(listen).await;
+            self.start(listen.clone(), cid).await;
+        }
+        true
+    }
+
+    /// Wait for the visitor listeners, they end once shut down
+    pub(in crate::server) async fn close(self) {
+        let visitors = self.visitors.into_values().map(|(_, task)| task);
+        for task in visitors.chain(self.vhosts.into_values()).flatten() {
+            task.await;
+        }
+    }
+
+    async fn start(&mut self, listen: Listen, cid: ClientId) {
+        let id = ListenerId::Visitor(listen.clone(), cid.clone());
+        let listeners = self.share.listeners.clone();
+        let failed = self.failed.clone();
+        if self.disabled.contains(&listen) {
+            listeners.set(&id, ListenState::Disabled);
+            self.visitors.insert(listen, (cid, None));
+            return;
+        }
+        let task = match Bound::bind(&listen).await {
+            Ok(bound) => {
+                listeners.set_up(&id);
+                let (cid, share) = (cid.clone(), self.share.clone());
+                Some(task::spawn(async move {
+                    let r = bound.serve(cid, &share).await;
+                    if let Err(e) = listeners.done(id, r) {
+                        let _ = failed.unbounded_send(e);
+                    }
+                }))
+            }
+            Err(e) => {
+                if let Err(e) = listeners.done(id, Err(e)) {
+                    let _ = failed.unbounded_send(e);
+                }
+                None
+            }
+        };
+        self.visitors.insert(listen, (cid, task));
+    }
+
+    async fn start_vhost(&mut self, listen: VHostListen) {
+        let id = ListenerId::VHost(listen.clone());
+        let listeners = self.share.listeners.clone();
+        let failed = self.failed.clone();
+        let task = match TcpListener::bind(listen.socket()).await {
+            Ok(tcp) => {
+                listeners.set_up(&id);
+                let (listen, share) = (listen.clone(), self.share.clone());
+                Some(task::spawn(async move {
+                    let r = visitor::vhost(tcp, listen, share).await;
+                    if let Err(e) = listeners.done(id, r) {
+                        let _ = failed.unbounded_send(e);
+                    }
+                }))
+            }
+            Err(e) => {
+                if let Err(e) = listeners.done(id, Err(e.into())) {
+                    let _ = failed.unbounded_send(e);
+                }
+                None
+            }
+        };
+        self.vhosts.insert(listen, task);
+    }
+
+    async fn stop_vhost(&mut self, listen: &VHostListen) {
+        if let Some(task) = self.vhosts.remove(listen) {
+            if let Some(task) = task {
+                task.cancel().await;
+            }
+            let id = ListenerId::VHost(listen.clone());
+            let _ = self.share.listeners.done(id, Ok(()));
+        }
+    }
+
+    async fn stop(&mut self, listen: &Listen) {
+        if let Some((cid, task)) = self.visitors.remove(listen) {
+            if let Some(task) = task {
+                task.cancel().await;
+            }
+            let id = ListenerId::Visitor(listen.clone(), cid);
+            let _ = self.share.listeners.done(id, Ok(()));
+        }
+    }
+}
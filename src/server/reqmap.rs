@@ -1,18 +1,29 @@
+use crate::protocol::auth::Token;
 use crate::protocol::net_proto::Establish;
-use async_std::net::TcpStream;
+use crate::stream::BoxStream;
 use async_std::stream::StreamExt;
 use futures::channel::mpsc::UnboundedReceiver;
 use futures::channel::oneshot;
+use log::warn;
 use std::collections::HashMap;
 
 pub enum ReqMapMessage {
     Set(ReqStat),
-    Take(oneshot::Sender<Option<ReqStat>>),
+    /// Take the pending request only if the token matches
+    Take(Token, oneshot::Sender<Option<ReqStat>>),
     Unset,
 }
 
 pub enum ReqStat {
-    Syn(oneshot::Sender<TcpStream>),
+    Syn(Token, oneshot::Sender<BoxStream>),
+}
+
+impl ReqStat {
+    fn token(&self) -> Token {
+        match self {
+            ReqStat::Syn(token, _) => *token,
+        }
+    }
 }
 
 pub async fn actor(mut events: UnboundedReceiver<(Establish, ReqMapMessage)>) {
@@ -24,8 +35,16 @@ pub async fn actor(mut events: UnboundedReceiver<(Establish, ReqMapMessage)>) {
             Msg::Set(stat) => {
                 map.insert(est, stat);
             }
-            Msg::Take(send) => {
-                let _ = send.send(map.remove(&est));
+            Msg::Take(token, send) => {
+                let stat = match map.get(&est) {
+                    Some(stat) if stat.token() == token => map.remove(&est),
+                    Some(_) => {
+                        warn!(target: "shadow-peer", "Invalid token for {:?}", est);
+                        None
+                    }
+                    None => None,
+                };
+                let _ = send.send(stat);
             }
             Msg::Unset => {
                 map.remove(&est);
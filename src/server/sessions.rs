@@ -0,0 +1,82 @@
+use crate::protocol::net_proto::Establish;
+use crate::protocol::ClientId;
+use crate::shutdown::SessionGuard;
+use crate::shutdown::Shutdown;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Visitor sessions being served
+#[derive(Clone, Default)]
+pub(in crate::server) struct Sessions {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    next: u64,
+    visit: HashMap<u64, Visit>,
+}
+
+#[derive(Clone)]
+pub(in crate::server) struct Visit {
+    pub(in crate::server) client: ClientId,
+    pub(in crate::server) est: Establish,
+    pub(in crate::server) start: Instant,
+    /// The client has connected the session
+    pub(in crate::server) open: bool,
+}
+
+/// A session counted by `Shutdown` and listed by `Sessions` until dropped
+pub(in crate::server) struct Entry {
+    _guard: SessionGuard,
+    sessions: Sessions,
+    id: u64,
+}
+
+impl Sessions {
+    pub(in crate::server) fn enter(
+        &self,
+        shutdown: &Shutdown,
+        client: &ClientId,
+        est: &Establish,
+    ) -> Entry {
+        let mut inner = self.inner.lock().unwrap();
+        let id = inner.next;
+        inner.next += 1;
+        let visit = Visit {
+            client: client.clone(),
+            est: est.clone(),
+            start: Instant::now(),
+            open: false,
+        };
+        inner.visit.insert(id, visit);
+        Entry {
+            _guard: shutdown.enter(),
+            sessions: self.clone(),
+            id,
+        }
+    }
+
+    pub(in crate::server) fn list(&self) -> Vec<(u64, Visit)> {
+        let inner = self.inner.lock().unwrap();
+        let visit = inner.visit.iter().map(|(id, v)| (*id, v.clone()));
+        visit.collect()
+    }
+}
+
+impl Entry {
+    pub(in crate::server) fn open(&self) {
+        let mut inner = self.sessions.inner.lock().unwrap();
+        if let Some(visit) = inner.visit.get_mut(&self.id) {
+            visit.open = true;
+        }
+    }
+}
+
+impl Drop for Entry {
+    fn drop(&mut self) {
+        self.sessions.inner.lock().unwrap().visit.remove(&self.id);
+    }
+}
@@ -0,0 +1,146 @@
+use crate::protocol::net_proto::Listen;
+use crate::protocol::ClientId;
+use std::fmt;
+use std::net::SocketAddr;
+
+/// A visitor listener shared by several clients, each visitor is routed by
+/// the host name it asks for
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum VHostListen {
+    /// By the `Host` header of the first HTTP request
+    Http(SocketAddr),
+    /// By the server name (SNI) of the TLS ClientHello, the stream is
+    /// forwarded as is, still encrypted
+    Tls(SocketAddr),
+}
+
+impl VHostListen {
+    pub fn socket(&self) -> SocketAddr {
+        match self {
+            VHostListen::Http(socket) | VHostListen::Tls(socket) => *socket,
+        }
+    }
+
+    pub fn proto(&self) -> &'static str {
+        match self {
+            VHostListen::Http(_) => "http",
+            VHostListen::Tls(_) => "tls",
+        }
+    }
+}
+
+impl fmt::Display for VHostListen {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.proto(), self.socket())
+    }
+}
+
+/// A visitor listener of one client or shared by virtual hosts, as access
+/// lists and limits are given for
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(in crate::server) enum VisitorListen {
+    Listen(Listen),
+    VHost(VHostListen),
+}
+
+impl From<Listen> for VisitorListen {
+    fn from(listen: Listen) -> VisitorListen {
+        VisitorListen::Listen(listen)
+    }
+}
+
+impl From<VHostListen> for VisitorListen {
+    fn from(listen: VHostListen) -> VisitorListen {
+        VisitorListen::VHost(listen)
+    }
+}
+
+impl fmt::Display for VisitorListen {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VisitorListen::Listen(Listen::Tcp(socket)) => write!(f, "listen tcp {}", socket),
+            VisitorListen::Listen(Listen::Udp(socket)) => write!(f, "listen udp {}", socket),
+            VisitorListen::VHost(listen) => write!(f, "vhost {}", listen),
+        }
+    }
+}
+
+/// Where visitors asking for `host` go
+#[derive(Clone, Debug, PartialEq)]
+pub struct VHost {
+    /// A host name in lower case, `*.example.com` for its subdomains or `*`
+    /// for any host
+    pub host: String,
+    pub client: ClientId,
+    /// Port of the client's port map the visitors are mapped by
+    pub port: u16,
+}
+
+impl VHost {
+    fn matches(&self, host: &str) -> bool {
+        match self.host.strip_prefix('*') {
+            Some("") => true,
+            Some(domain) => domain.starts_with('.') && host.ends_with(domain),
+            None => self.host == host,
+        }
+    }
+}
+
+/// The virtual host of `vhosts` for `host`, an exact match before the
+/// longest wildcard
+pub(in crate::server) fn find<'a>(vhosts: &'a [VHost], host: Option<&str>) -> Option<&'a VHost> {
+    let host = host.unwrap_or("").to_ascii_lowercase();
+    let found = vhosts.iter().filter(|v| v.matches(&host));
+    found.max_by_key(|v| (v.host == host, v.host.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vhosts(hosts: &[&str]) -> Vec<VHost> {
+        let vhost = |(port, host): (usize, &&str)| VHost {
+            host: host.to_string(),
+            client: "c1".to_string(),
+            port: port as u16,
+        };
+        hosts.iter().enumerate().map(vhost).collect()
+    }
+
+    fn host<'a>(vhosts: &'a [VHost], host: Option<&str>) -> Option<&'a str> {
+        find(vhosts, host).map(|v| v.host.as_str())
+    }
+
+    #[test]
+    fn exact_before_wildcards() {
+        let vhosts = vhosts(&["*", "*.example.com", "*.a.example.com", "a.example.com"]);
+        assert_eq!(host(&vhosts, Some("a.example.com")), Some("a.example.com"));
+        assert_eq!(
+            host(&vhosts, Some("b.a.example.com")),
+            Some("*.a.example.com")
+        );
+        assert_eq!(host(&vhosts, Some("b.example.com")), Some("*.example.com"));
+        assert_eq!(host(&vhosts, Some("other.test")), Some("*"));
+    }
+
+    #[test]
+    fn case_insensitive() {
+        let vhosts = vhosts(&["a.example.com", "*.b.test"]);
+        assert_eq!(host(&vhosts, Some("A.Example.COM")), Some("a.example.com"));
+        assert_eq!(host(&vhosts, Some("X.B.TEST")), Some("*.b.test"));
+    }
+
+    #[test]
+    fn wildcard_needs_subdomain() {
+        let vhosts = vhosts(&["*.example.com"]);
+        assert_eq!(host(&vhosts, Some("example.com")), None);
+        assert_eq!(host(&vhosts, Some("badexample.com")), None);
+        assert_eq!(host(&vhosts, Some("a.example.com")), Some("*.example.com"));
+    }
+
+    #[test]
+    fn no_host() {
+        assert_eq!(host(&vhosts(&["a.test", "*.test"]), None), None);
+        assert_eq!(host(&vhosts(&["a.test", "*"]), None), Some("*"));
+    }
+}
@@ -1,8 +1,240 @@
-pub(in crate::server) use self::tcp::tcp;
+pub(in crate::server) use self::vhost::vhost;
+use super::client::StreamShare;
+use super::limits::Slot;
 use super::reqmap::ReqMapMessage;
 use super::reqmap::ReqStat;
-use super::Client;
+use super::sessions::Sessions;
 use super::ClientMap;
 use super::ReqMapSender;
+use crate::error::Error;
+use crate::error::FastResult;
+use crate::error::Result;
+use crate::metrics::Counter;
+use crate::metrics::Metrics;
+use crate::metrics::SESSIONS;
+use crate::metrics::VISITOR_BYTES;
+use crate::protocol::auth;
+use crate::protocol::net_proto::Establish;
+use crate::protocol::net_proto::Listen;
+use crate::protocol::write_protocol;
+use crate::protocol::ClientId;
+use crate::protocol::Protocol;
+use crate::protocol::BASE_VERSION;
+use crate::stream::BoxStream;
+use async_std::future::timeout;
+use async_std::net::TcpListener;
+use async_std::net::UdpSocket;
+use futures::channel::mpsc::UnboundedSender;
+use futures::channel::oneshot;
+use futures::channel::oneshot::Receiver;
+use log::warn;
+use std::net::SocketAddr;
+use std::time::Duration;
 
 mod tcp;
+mod udp;
+mod vhost;
+
+/// A bound visitor listener
+pub(in crate::server) enum Bound {
+    Tcp(TcpListener),
+    Udp(UdpSocket),
+}
+
+impl Bound {
+    pub(in crate::server) async fn bind(listen: &Listen) -> Result<Bound> {
+        match listen {
+            Listen::Tcp(socket) => Ok(Bound::Tcp(TcpListener::bind(socket).await?)),
+            Listen::Udp(socket) => Ok(Bound::Udp(UdpSocket::bind(socket).await?)),
+        }
+    }
+
+    pub(in crate::server) fn local_addr(&self) -> Result<SocketAddr> {
+        match self {
+            Bound::Tcp(tcp) => Ok(tcp.local_addr()?),
+            Bound::Udp(udp) => Ok(udp.local_addr()?),
+        }
+    }
+
+    /// Forward visitors to client `id`, until shut down
+    pub(in crate::server) async fn serve(self, id: ClientId, share: &StreamShare) -> Result<()> {
+        match self {
+            Bound::Tcp(listener) => tcp::tcp(listener, id, share.clone()).await,
+            Bound::Udp(socket) => udp::udp(socket, id, share.clone()).await,
+        }
+    }
+}
+
+struct StreamWaitor<'a> {
+    req: &'a ReqMapSender,
+    establish: Establish,
+    recv: Option<Receiver<BoxStream>>,
+    _pending: Slot,
+}
+
+impl<'a> StreamWaitor<'a> {
+    fn register(
+        req: &'a ReqMapSender,
+        est: Establish,
+        estab_sender: &UnboundedSender<Protocol>,
+        pending: Slot,
+    ) -> FastResult<Self> {
+        let (send, recv) = oneshot::channel();
+        let token = auth::token();
+        let stat = ReqStat::Syn(token, send);
+        let msg = ReqMapMessage::Set(stat);
+        let protocol = Protocol::Establish(est.clone(), token);
+        req.unbounded_send((est.clone(), msg))?;
+        estab_sender.unbounded_send(protocol)?;
+        Ok(StreamWaitor {
+            req,
+            establish: est,
+            recv: Some(recv),
+            _pending: pending,
+        })
+    }
+
+    /// Wait for the worker connection, the request is left to be unset on
+    /// drop unless it was answered.
+    async fn recv(&mut self, tmout: Duration) -> Result<BoxStream> {
+        let recv = match &mut self.recv {
+            Some(r) => r,
+            None => return Err(Error::InvalidOperation("Waited already".to_string())),
+        };
+        let r = timeout(tmout, recv).await?;
+        self.recv = None;
+        match r {
+            Ok(stream) => Ok(stream),
+            Err(_) => Err(Error::InvalidOperation("Request dropped".to_string())),
+        }
+    }
+}
+
+impl<'a> Drop for StreamWaitor<'a> {
+    fn drop(&mut self) {
+        if self.recv.is_some() {
+            let msg = ReqMapMessage::Unset;
+            let _ = self.req.unbounded_send((self.establish.clone(), msg));
+        }
+    }
+}
+
+/// Metrics of one visitor listener
+struct Meters {
+    bytes_in: Counter,
+    bytes_out: Counter,
+    opened: Counter,
+    failed: Counter,
+    timeout: Counter,
+    /// Dropped over the connection limits
+    rejected: Counter,
+    /// Dropped by the access lists
+    denied: Counter,
+}
+
+impl Meters {
+    fn new(metrics: &Metrics, listen: String, client: &ClientId) -> Meters {
+        let labels = [("listen", listen.as_str()), ("client", client.as_str())];
+        let bytes = |direction| {
+            let labels = [labels[0], labels[1], ("direction", direction)];
+            metrics.counter(&VISITOR_BYTES, &labels)
+        };
+        let sessions = |result| {
+            let labels = [labels[0], labels[1], ("result", result)];
+            metrics.counter(&SESSIONS, &labels)
+        };
+        Meters {
+            bytes_in: bytes("in"),
+            bytes_out: bytes("out"),
+            opened: sessions("opened"),
+            failed: sessions("failed"),
+            timeout: sessions("timeout"),
+            rejected: sessions("rejected"),
+            denied: sessions("denied"),
+        }
+    }
+}
+
+/// Connect `est` through client `id` and count the result. The stream is
+/// given with the session counted on the client's connection serving it.
+async fn connect(
+    id: &ClientId,
+    est: Establish,
+    cli: &ClientMap,
+    req: &ReqMapSender,
+    meters: &Meters,
+    tmout: Duration,
+    max_pending: usize,
+) -> Option<(BoxStream, Slot)> {
+    let r = connect_impl(id, est, cli, req, tmout, max_pending).await;
+    match &r {
+        Ok(_) => meters.opened.inc(),
+        Err(Error::Timeout(_)) => meters.timeout.inc(),
+        Err(Error::Rejected(_)) => meters.rejected.inc(),
+        Err(_) => meters.failed.inc(),
+    }
+    r.ok()
+}
+
+/// Ask one of the connections of client `id` to establish `est`, wait for its
+/// worker connection. A stream is opened directly if the connection is
+/// multiplexed, or taken from its pool if any connection is parked. No more
+/// than `max_pending` visitors of the client wait for a worker connection at
+/// once.
+async fn connect_impl(
+    id: &ClientId,
+    est: Establish,
+    cli: &ClientMap,
+    req: &ReqMapSender,
+    tmout: Duration,
+    max_pending: usize,
+) -> Result<(BoxStream, Slot)> {
+    let gone = || Error::InvalidOperation(format!("Client {} not connected", id));
+    let client = cli.read().await.get(id).and_then(|instances| {
+        let client = instances.pick()?;
+        let busy = Slot::hold(&client.active);
+        let (send, mux, pool) = (&client.estab_sender, &client.mux, &client.pool);
+        Some((
+            send.clone(),
+            mux.clone(),
+            pool.clone(),
+            instances.pending.clone(),
+            busy,
+        ))
+    });
+    let (estab_sender, mux, pool, pending, busy) = client.ok_or_else(gone)?;
+    if let Some(mux) = mux {
+        let (sid, stream) = mux.open();
+        let proto = Protocol::Open(sid, est);
+        estab_sender.unbounded_send(proto).map_err(|_| gone())?;
+        return Ok((Box::new(stream), busy));
+    }
+    while let Some(mut stream) = pool.pop().await {
+        let proto = Protocol::Establish(est.clone(), pool.token);
+        if write_protocol(&mut stream, BASE_VERSION, &proto)
+            .await
+            .is_ok()
+        {
+            return Ok((stream, busy));
+        }
+    }
+
+    let pending = match Slot::take(&pending, max_pending) {
+        Some(pending) => pending,
+        None => {
+            let e = format!("Client {} has too many visitors pending", id);
+            return Err(Error::Rejected(e));
+        }
+    };
+    let mut sw = match StreamWaitor::register(req, est, &estab_sender, pending) {
+        Ok(sw) => sw,
+        Err(e) => {
+            warn!(target: "shadow-peer", "{}", e);
+            return Err(gone());
+        }
+    };
+
+    // Wait for client connection
+    let stream = sw.recv(tmout).await?;
+    Ok((stream, busy))
+}
@@ -1,131 +1,175 @@
-use super::Client;
-use super::ClientMap;
-use super::ReqMapMessage;
-use super::ReqMapSender;
-use super::ReqStat;
-use crate::error::err_exit;
+use super::connect;
+use super::Meters;
+use super::Slot;
+use super::StreamShare;
 use crate::error::Error;
-use crate::error::FastResult;
 use crate::error::Result;
 use crate::protocol::net_proto::Establish;
+use crate::protocol::net_proto::Listen;
 use crate::protocol::net_proto::TcpEstablish;
 use crate::protocol::ClientId;
-use crate::protocol::Protocol;
-use async_std::future::timeout;
+use crate::proxy;
+use crate::ratelimit::Limited;
+use crate::server::vhost::VisitorListen;
+use crate::stream;
+use crate::stream::Counted;
+use crate::utils::Backoff;
 use async_std::io;
 use async_std::net::TcpListener;
 use async_std::net::TcpStream;
 use async_std::stream::StreamExt;
+use async_std::sync::Arc;
 use async_std::task;
-use futures::channel::oneshot;
-use futures::channel::oneshot::Receiver;
-use futures::future::FutureExt;
+use futures::FutureExt;
 use log::warn;
 use std::net::SocketAddr;
-use std::time::Duration;
+use std::sync::atomic::AtomicUsize;
 
-pub(in crate::server) async fn tcp(
-    listen: SocketAddr,
-    id: ClientId,
-    cli: ClientMap,
-    req: ReqMapSender,
-) -> Result<()> {
-    let port = listen.port() as u32;
-    let tcp = TcpListener::bind(listen).await?;
+pub(super) async fn tcp(tcp: TcpListener, id: ClientId, share: StreamShare) -> Result<()> {
+    let local = tcp.local_addr()?;
+    let port = local.port() as u32;
+    let meters = Arc::new(Meters::new(&share.metrics, format!("tcp {}", local), &id));
+    let listen = Listen::Tcp(local);
+    let visitors = Arc::new(Visitors {
+        id,
+        listen: listen.clone().into(),
+        active: Default::default(),
+        share: share.clone(),
+        meters,
+    });
     let mut tcp = tcp.incoming();
-    while let Some(stream) = tcp.next().await {
+    let mut backoff = Backoff::new();
+    loop {
+        let stream = futures::select! {
+            stream = tcp.next().fuse() => stream,
+            _ = share.shutdown.wait().fuse() => return Ok(()),
+        };
         let stream = match stream {
-            Ok(stream) => stream,
-            Err(_) => continue,
+            Some(Ok(stream)) => stream,
+            None => break,
+            Some(Err(e)) => {
+                warn!(target: "shadow-peer", "TCP accept on port {}: {}", port, e);
+                backoff.wait().await;
+                continue;
+            }
+        };
+        backoff.reset();
+        if share.proxied.read().await.contains(&listen) {
+            // Held from before the PROXY header, which may never come
+            if let Some(slot) = visitors.slot() {
+                task::spawn(visitors.clone().proxied(stream, slot));
+            }
+            continue;
+        }
+        let src = match stream.peer_addr() {
+            Ok(src) if visitors.permits(src) => src,
+            _ => continue,
         };
-        let _ = tcp_stream(stream, id.clone(), &cli, &req).await;
+        if let Some(slot) = visitors.slot() {
+            visitors.visit(stream, src, slot);
+        }
     }
-    err_exit(65, Error::ListenFail("TCP", port))
+    Err(Error::ListenFail("TCP", port))
 }
 
-struct StreamWaitor<'a> {
-    req: &'a ReqMapSender,
-    establish: Establish,
-    recv: Option<Receiver<TcpStream>>,
+/// A visitor listener of client `id`, shared by its sessions
+struct Visitors {
+    id: ClientId,
+    listen: VisitorListen,
+    /// Sessions of the listener
+    active: Arc<AtomicUsize>,
+    share: StreamShare,
+    meters: Arc<Meters>,
 }
 
-impl<'a> StreamWaitor<'a> {
-    fn register(req: &'a ReqMapSender, est: Establish, cli: &Client) -> FastResult<Self> {
-        let (send, recv) = oneshot::channel();
-        let stat = ReqStat::Syn(send);
-        let msg = ReqMapMessage::Set(stat);
-        let protocol = Protocol::Establish(est.clone());
-        req.unbounded_send((est.clone(), msg))?;
-        cli.estab_sender.unbounded_send(protocol)?;
-        Ok(StreamWaitor {
-            req,
-            establish: est,
-            recv: Some(recv),
-        })
+impl Visitors {
+    /// Whether visitors from `src` are allowed, counted as denied if not
+    fn permits(&self, src: SocketAddr) -> bool {
+        let permits = self.share.gate.permits(&self.listen, src.ip());
+        if !permits {
+            self.meters.denied.inc();
+        }
+        permits
+    }
+
+    /// Serve a visitor from `src` in `slot`, unless it is over the limits
+    fn visit(&self, stream: TcpStream, src: SocketAddr, slot: Slot) {
+        match self.share.limiters.admit(src.ip()) {
+            true => {
+                let _ = tcp_stream(stream, src, self, slot);
+            }
+            false => self.meters.rejected.inc(),
+        }
     }
 
-    async fn recv(&mut self) -> Option<TcpStream> {
-        const TMOUT: u64 = 10;
-        let mut recv = None;
-        std::mem::swap(&mut recv, &mut self.recv);
-        let recv = match recv {
-            Some(r) => r,
-            None => return None,
+    /// Serve a visitor connected through a load balancer, from the address
+    /// in the PROXY header it sent first
+    async fn proxied(self: Arc<Self>, mut stream: TcpStream, slot: Slot) {
+        let tmout = self.share.timeouts.read;
+        let header = io::timeout(tmout, proxy::read_header(&mut stream));
+        let header = futures::select! {
+            header = header.fuse() => header,
+            _ = self.share.shutdown.wait().fuse() => return,
+        };
+        let src = match header {
+            Ok(Some((src, _))) => src,
+            Ok(None) => match stream.peer_addr() {
+                Ok(src) => src,
+                Err(_) => return,
+            },
+            Err(e) => {
+                let peer = stream
+                    .peer_addr()
+                    .map(|p| p.to_string())
+                    .unwrap_or_default();
+                warn!(target: "shadow-peer", "PROXY header from {}: {}", peer, e);
+                return;
+            }
         };
-        match timeout(Duration::from_secs(TMOUT), recv).await {
-            Ok(Ok(stream)) => Some(stream),
-            _ => None, // Canceled / Timeout
+        if self.permits(src) {
+            self.visit(stream, src, slot);
         }
     }
-}
 
-impl<'a> Drop for StreamWaitor<'a> {
-    fn drop(&mut self) {
-        if let Some(_) = self.recv {
-            let msg = ReqMapMessage::Unset;
-            let _ = self.req.unbounded_send((self.establish.clone(), msg));
+    /// One of the sessions of the listener, unless all are taken
+    fn slot(&self) -> Option<Slot> {
+        let max = self.share.limiters.max_sessions(&self.listen);
+        let slot = Slot::take(&self.active, max);
+        if slot.is_none() {
+            self.meters.rejected.inc();
         }
+        slot
     }
 }
 
-async fn tcp_stream(
-    stream: TcpStream,
-    id: ClientId,
-    cli: &ClientMap,
-    req: &ReqMapSender,
-) -> Result<()> {
-    let src = stream.peer_addr()?;
+fn tcp_stream(stream: TcpStream, src: SocketAddr, visitors: &Visitors, slot: Slot) -> Result<()> {
+    let (id, share) = (visitors.id.clone(), &visitors.share);
+    share.timeouts.keepalive(&stream);
     let dest = stream.local_addr()?;
+    let limits = share.limiters.of(&visitors.listen, &id);
     let establish = TcpEstablish { src, dest };
     let establish = Establish::Tcp(establish);
-    let cli = cli.clone();
-    let req = req.clone();
+    let session = share.sessions.enter(&share.shutdown, &id, &establish);
+    let cli = share.cli.clone();
+    let req = share.req.clone();
+    let meters = visitors.meters.clone();
+    let timeouts = share.timeouts;
+    let max_pending = share.limiters.max_pending();
 
     task::spawn(async move {
-        let mut sw = match { cli.read().await.get(&id) } {
-            Some(cli) => match StreamWaitor::register(&req, establish.clone(), cli) {
-                Ok(sw) => sw,
-                Err(e) => {
-                    warn!(target: "shadow-peer", "{}", e);
-                    return;
-                }
-            },
-            None => return,
-        };
-
-        // Wait for client connection
-        let cli_stream = match sw.recv().await {
+        let _slot = slot;
+        let wait = timeouts.connect;
+        let connect = connect(&id, establish, &cli, &req, &meters, wait, max_pending);
+        let (cli_stream, _busy) = match connect.await {
             Some(s) => s,
             None => return,
         };
+        session.open();
 
         // Sync
-        let (vr, vw) = &mut (&stream, &stream);
-        let (cr, cw) = &mut (&cli_stream, &cli_stream);
-        futures::select! {
-            _ = io::copy(vr, cw).fuse() => {}
-            _ = io::copy(cr, vw).fuse() => {}
-        }
+        let (bytes_in, bytes_out) = (meters.bytes_in.clone(), meters.bytes_out.clone());
+        let stream = Counted::new(Limited::new(stream, limits), bytes_in, bytes_out);
+        let _ = stream::pipe(Box::new(stream), cli_stream, timeouts.idle).await;
     });
     Ok(())
 }
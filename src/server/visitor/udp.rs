@@ -0,0 +1,190 @@
+use super::connect;
+use super::ClientMap;
+use super::Meters;
+use super::ReqMapSender;
+use super::Sessions;
+use super::Slot;
+use super::StreamShare;
+use crate::error::Result;
+use crate::protocol::datagram::datagram_stream;
+use crate::protocol::datagram::write_datagram;
+use crate::protocol::net_proto::Establish;
+use crate::protocol::net_proto::Listen;
+use crate::protocol::net_proto::UdpEstablish;
+use crate::protocol::ClientId;
+use crate::ratelimit::Dir;
+use crate::ratelimit::Limiter;
+use crate::server::access::Gate;
+use crate::server::limits::Limiters;
+use crate::server::vhost::VisitorListen;
+use crate::shutdown::Shutdown;
+use crate::timeouts::Timeouts;
+use crate::utils::Backoff;
+use async_std::net::UdpSocket;
+use async_std::stream::StreamExt;
+use async_std::sync::Arc;
+use async_std::sync::Mutex;
+use async_std::task;
+use futures::channel::mpsc;
+use futures::channel::mpsc::Receiver;
+use futures::channel::mpsc::Sender;
+use futures::AsyncReadExt;
+use futures::FutureExt;
+use futures_timer::Delay;
+use log::warn;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::AtomicUsize;
+
+/// Datagrams queued per session while the worker connection is pending
+const SESSION_QUEUE: usize = 64;
+/// Sessions at once on one listener, unless `max_sessions` is lower
+const MAX_SESSIONS: usize = 4096;
+
+struct UdpShare {
+    socket: UdpSocket,
+    session: Mutex<HashMap<SocketAddr, Sender<Vec<u8>>>>,
+    id: ClientId,
+    cli: ClientMap,
+    req: ReqMapSender,
+    shutdown: Shutdown,
+    sessions: Sessions,
+    meters: Meters,
+    limiters: Limiters,
+    gate: Gate,
+    /// Sessions of this listener
+    active: Arc<AtomicUsize>,
+    /// Ends the sessions of this listener
+    closed: Shutdown,
+    timeouts: Timeouts,
+}
+
+/// Ends all sessions once the listener is gone
+struct Teardown(Arc<UdpShare>);
+
+impl Drop for Teardown {
+    fn drop(&mut self) {
+        self.0.closed.shutdown();
+    }
+}
+
+pub(super) async fn udp(socket: UdpSocket, id: ClientId, share: StreamShare) -> Result<()> {
+    let dest = socket.local_addr()?;
+    let listen = VisitorListen::from(Listen::Udp(dest));
+    let meters = Meters::new(&share.metrics, format!("udp {}", dest), &id);
+    let session = Mutex::new(HashMap::new());
+    let share = Arc::new(UdpShare {
+        socket,
+        session,
+        id,
+        cli: share.cli,
+        req: share.req,
+        shutdown: share.shutdown,
+        sessions: share.sessions,
+        meters,
+        limiters: share.limiters,
+        gate: share.gate,
+        active: Default::default(),
+        closed: Shutdown::new(),
+        timeouts: share.timeouts,
+    });
+    let _teardown = Teardown(share.clone());
+    let mut buf = vec![0u8; u16::MAX as usize];
+    let mut backoff = Backoff::new();
+    loop {
+        let r = futures::select! {
+            r = share.socket.recv_from(&mut buf).fuse() => r,
+            _ = share.shutdown.wait().fuse() => return Ok(()),
+        };
+        let (len, src) = match r {
+            Ok(r) => r,
+            Err(e) => {
+                warn!(target: "shadow-peer", "UDP receive on {}: {}", dest, e);
+                backoff.wait().await;
+                continue;
+            }
+        };
+        backoff.reset();
+        let data = buf[..len].to_vec();
+        let mut map = share.session.lock().await;
+        match map.get_mut(&src) {
+            Some(send) if !send.is_closed() => {
+                let _ = send.try_send(data); // Drop on overflow
+            }
+            _ if !share.gate.permits(&listen, src.ip()) => share.meters.denied.inc(),
+            _ => {
+                let max = share.limiters.max_sessions(&listen).min(MAX_SESSIONS);
+                let slot = match Slot::take(&share.active, max) {
+                    Some(slot) if share.limiters.admit(src.ip()) => slot,
+                    _ => {
+                        share.meters.rejected.inc();
+                        continue;
+                    }
+                };
+                let (mut send, recv) = mpsc::channel(SESSION_QUEUE);
+                let _ = send.try_send(data);
+                map.insert(src, send);
+                let establish = UdpEstablish { src, dest };
+                task::spawn(udp_session(establish, recv, share.clone(), slot));
+            }
+        }
+    }
+}
+
+async fn udp_session(
+    est: UdpEstablish,
+    mut recv: Receiver<Vec<u8>>,
+    share: Arc<UdpShare>,
+    _slot: Slot,
+) {
+    let src = est.src;
+    let est = Establish::Udp(est);
+    let session = share.sessions.enter(&share.shutdown, &share.id, &est);
+    let listen = VisitorListen::from(Listen::Udp(est.dest()));
+    let limits = share.limiters.of(&listen, &share.id);
+    let (cli, req, wait) = (&share.cli, &share.req, share.timeouts.connect);
+    let max_pending = share.limiters.max_pending();
+    let connect = connect(&share.id, est, cli, req, &share.meters, wait, max_pending);
+    let connected = futures::select! {
+        connected = connect.fuse() => connected,
+        _ = share.closed.wait().fuse() => None,
+    };
+    if let Some((cli_stream, _busy)) = connected {
+        session.open();
+        let (reader, mut writer) = cli_stream.split();
+        let mut frames = Box::pin(datagram_stream(reader));
+        loop {
+            let idle = Delay::new(share.timeouts.udp_idle);
+            futures::select! {
+                frame = frames.next().fuse() => match frame {
+                    // Dropped over the limits
+                    Some(Ok(data)) if !Limiter::admit(&limits, Dir::Out, data.len()) => {}
+                    Some(Ok(data)) => {
+                        share.meters.bytes_out.add(data.len() as u64);
+                        let _ = share.socket.send_to(&data, src).await;
+                    }
+                    _ => break,
+                },
+                data = recv.next().fuse() => match data {
+                    Some(data) if !Limiter::admit(&limits, Dir::In, data.len()) => {}
+                    Some(data) => {
+                        share.meters.bytes_in.add(data.len() as u64);
+                        if write_datagram(&mut writer, &data).await.is_err() {
+                            break;
+                        }
+                    }
+                    None => break,
+                },
+                _ = idle.fuse() => break,
+                _ = share.closed.wait().fuse() => break,
+            }
+        }
+    }
+
+    // Only remove the session if it is still ours
+    recv.close();
+    let mut map = share.session.lock().await;
+    if map.get(&src).map(|send| send.is_closed()).unwrap_or(false) {
+        map.remove(&src);
+    }
+}
@@ -0,0 +1,376 @@
+use super::connect;
+use super::Meters;
+use super::Slot;
+use super::StreamShare;
+use crate::error::Error;
+use crate::error::Result;
+use crate::protocol::net_proto::Establish;
+use crate::protocol::net_proto::TcpEstablish;
+use crate::protocol::ClientId;
+use crate::ratelimit::Limited;
+use crate::server::vhost;
+use crate::server::vhost::VHostListen;
+use crate::server::vhost::VisitorListen;
+use crate::stream;
+use crate::stream::Counted;
+use crate::utils::Backoff;
+use async_std::io;
+use async_std::net::TcpListener;
+use async_std::net::TcpStream;
+use async_std::stream::StreamExt;
+use async_std::sync::Arc;
+use async_std::task;
+use futures::io::AsyncRead;
+use futures::AsyncReadExt;
+use futures::AsyncWriteExt;
+use futures::FutureExt;
+use log::warn;
+use std::net::SocketAddr;
+use std::sync::atomic::AtomicUsize;
+
+/// Longest request head or TLS handshake read to find the host
+const HEAD_MAX: usize = 16 * 1024;
+/// TLS record content type of handshakes
+const TLS_HANDSHAKE: u8 = 0x16;
+/// TLS handshake message type of the ClientHello
+const TLS_CLIENT_HELLO: u8 = 1;
+/// TLS extension type of the server name
+const TLS_SERVER_NAME: u16 = 0;
+
+/// Route the visitors of `listener` to clients by host, until shut down
+pub(in crate::server) async fn vhost(
+    listener: TcpListener,
+    shared: VHostListen,
+    share: StreamShare,
+) -> Result<()> {
+    let visitors = Arc::new(Visitors {
+        listen: shared.clone().into(),
+        active: Default::default(),
+        // Of visitors not routed to a client yet
+        meters: Meters::new(&share.metrics, shared.to_string(), &ClientId::new()),
+    });
+    let mut incoming = listener.incoming();
+    let mut backoff = Backoff::new();
+    loop {
+        let stream = futures::select! {
+            stream = incoming.next().fuse() => stream,
+            _ = share.shutdown.wait().fuse() => return Ok(()),
+        };
+        let stream = match stream {
+            Some(Ok(stream)) => stream,
+            None => break,
+            Some(Err(e)) => {
+                warn!(target: "shadow-peer", "Accept on {}: {}", shared, e);
+                backoff.wait().await;
+                continue;
+            }
+        };
+        backoff.reset();
+        task::spawn(route(
+            stream,
+            shared.clone(),
+            share.clone(),
+            visitors.clone(),
+        ));
+    }
+    Err(Error::ListenFail("TCP", shared.socket().port() as u32))
+}
+
+/// A listener shared by virtual hosts, access and limits apply to all of them
+struct Visitors {
+    listen: VisitorListen,
+    /// Sessions of the listener, counted from before the host is known
+    active: Arc<AtomicUsize>,
+    meters: Meters,
+}
+
+/// Read the head of the visitor's first request, or its TLS ClientHello, and
+/// hand it over to the client serving the host asked for, along with the rest
+/// of the stream
+async fn route(
+    mut stream: TcpStream,
+    shared: VHostListen,
+    share: StreamShare,
+    visitors: Arc<Visitors>,
+) {
+    let (src, local) = match (stream.peer_addr(), stream.local_addr()) {
+        (Ok(src), Ok(local)) => (src, local),
+        _ => return,
+    };
+    let listen = &visitors.listen;
+    if !share.gate.permits(listen, src.ip()) {
+        visitors.meters.denied.inc();
+        return reply(stream, &shared, "403 Forbidden").await;
+    }
+    let max = share.limiters.max_sessions(listen);
+    let _slot = match Slot::take(&visitors.active, max) {
+        Some(slot) if share.limiters.admit(src.ip()) => slot,
+        _ => {
+            visitors.meters.rejected.inc();
+            return reply(stream, &shared, "429 Too Many Requests").await;
+        }
+    };
+    let peek = io::timeout(share.timeouts.read, peek(&mut stream, &shared));
+    let peeked = futures::select! {
+        peeked = peek.fuse() => peeked,
+        _ = share.shutdown.wait().fuse() => return,
+    };
+    let (head, host) = match peeked {
+        Ok(peeked) => peeked,
+        Err(_) => return,
+    };
+    let vhost = match share.vhosts.read().await.get(&shared) {
+        Some(vhosts) => vhost::find(vhosts, host.as_deref()).cloned(),
+        None => None,
+    };
+    let vhost = match vhost {
+        Some(vhost) => vhost,
+        None => return reply(stream, &shared, "404 Not Found").await,
+    };
+    let id = vhost.client;
+    let meters = Meters::new(&share.metrics, shared.to_string(), &id);
+    share.timeouts.keepalive(&stream);
+
+    // Mapped by the client as if it came to the vhost's port
+    let dest = SocketAddr::new(local.ip(), vhost.port);
+    let establish = Establish::Tcp(TcpEstablish { src, dest });
+    let session = share.sessions.enter(&share.shutdown, &id, &establish);
+    let limits = share.limiters.of(listen, &id);
+    let (cli, req, wait) = (&share.cli, &share.req, share.timeouts.connect);
+    let max_pending = share.limiters.max_pending();
+    let connect = connect(&id, establish, cli, req, &meters, wait, max_pending);
+    let (mut cli_stream, _busy) = match connect.await {
+        Some(s) => s,
+        None => return reply(stream, &shared, "502 Bad Gateway").await,
+    };
+    session.open();
+    if cli_stream.write_all(&head).await.is_err() {
+        return;
+    }
+    meters.bytes_in.add(head.len() as u64);
+
+    // Sync
+    let (bytes_in, bytes_out) = (meters.bytes_in.clone(), meters.bytes_out.clone());
+    let stream = Counted::new(Limited::new(stream, limits), bytes_in, bytes_out);
+    let _ = stream::pipe(Box::new(stream), cli_stream, share.timeouts.idle).await;
+}
+
+/// Bytes read to find the host asked for, and the host
+async fn peek(
+    stream: &mut TcpStream,
+    shared: &VHostListen,
+) -> io::Result<(Vec<u8>, Option<String>)> {
+    match shared {
+        VHostListen::Http(_) => {
+            let head = read_head(stream).await?;
+            let host = http_host(&head);
+            Ok((head, host))
+        }
+        VHostListen::Tls(_) => {
+            let (records, hello) = read_hello(stream).await?;
+            Ok((records, server_name(&hello)))
+        }
+    }
+}
+
+/// Bytes read up to the end of the request head, or as many as allowed
+async fn read_head(stream: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let mut head = vec![];
+    let mut buf = [0u8; 4096];
+    while head.len() < HEAD_MAX && !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        match stream.read(&mut buf).await? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            len => head.extend_from_slice(&buf[..len]),
+        }
+    }
+    Ok(head)
+}
+
+/// The `Host` header of a request head, without the port
+fn http_host(head: &[u8]) -> Option<String> {
+    let head = String::from_utf8_lossy(head);
+    let lines = head.split("\r\n").skip(1).take_while(|l| !l.is_empty());
+    let mut headers = lines.filter_map(|l| l.split_once(':'));
+    let (_, host) = headers.find(|(name, _)| name.trim().eq_ignore_ascii_case("host"))?;
+    let host = host.trim();
+    let host = match host.strip_prefix('[') {
+        Some(v6) => v6.split(']').next()?,
+        None => host.split(':').next()?,
+    };
+    Some(host.to_string())
+}
+
+/// Records read up to the end of the ClientHello, or as many as allowed, and
+/// the handshake they carry
+async fn read_hello<R>(stream: &mut R) -> io::Result<(Vec<u8>, Vec<u8>)>
+where
+    R: AsyncRead + Unpin,
+{
+    let (mut records, mut hello) = (vec![], vec![]);
+    loop {
+        let mut header = [0u8; 5];
+        stream.read_exact(&mut header).await?;
+        if header[0] != TLS_HANDSHAKE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Not a TLS handshake",
+            ));
+        }
+        let mut record = vec![0u8; u16::from_be_bytes([header[3], header[4]]) as usize];
+        stream.read_exact(&mut record).await?;
+        records.extend_from_slice(&header);
+        records.extend_from_slice(&record);
+        hello.extend(record);
+        let whole = field(hello.get(1..).unwrap_or_default(), 3).is_some();
+        if whole || records.len() >= HEAD_MAX {
+            return Ok((records, hello));
+        }
+    }
+}
+
+/// The server name of a ClientHello handshake
+fn server_name(hello: &[u8]) -> Option<String> {
+    if *hello.first()? != TLS_CLIENT_HELLO {
+        return None;
+    }
+    let (hello, _) = field(&hello[1..], 3)?;
+    // Version and random, then session id, cipher suites and compression
+    let rest = hello.get(2 + 32..)?;
+    let (_, rest) = field(rest, 1)?;
+    let (_, rest) = field(rest, 2)?;
+    let (_, rest) = field(rest, 1)?;
+    let (mut extensions, _) = field(rest, 2)?;
+    while extensions.len() >= 2 {
+        let kind = u16::from_be_bytes([extensions[0], extensions[1]]);
+        let (data, next) = field(&extensions[2..], 2)?;
+        if kind == TLS_SERVER_NAME {
+            // A list of names, of which only host names are defined
+            let (names, _) = field(data, 2)?;
+            if *names.first()? != 0 {
+                return None;
+            }
+            let (name, _) = field(&names[1..], 2)?;
+            return String::from_utf8(name.to_vec()).ok();
+        }
+        extensions = next;
+    }
+    None
+}
+
+/// The field at the start of `data` after its `size` bytes long length, and
+/// what follows it
+fn field(data: &[u8], size: usize) -> Option<(&[u8], &[u8])> {
+    let len = data.get(..size)?;
+    let len = len.iter().fold(0, |len, &b| len << 8 | b as usize);
+    let data = &data[size..];
+    Some((data.get(..len)?, data.get(len..)?))
+}
+
+/// Tell an HTTP visitor why it is turned away, a TLS one is just closed
+async fn reply(mut stream: TcpStream, shared: &VHostListen, status: &str) {
+    if let VHostListen::Tls(_) = shared {
+        return;
+    }
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        status
+    );
+    let _ = stream.write_all(response.as_bytes()).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::io::Cursor;
+
+    /// A ClientHello asking for `name`, if any, after another extension
+    fn client_hello(name: Option<&str>) -> Vec<u8> {
+        let mut body = vec![3, 3];
+        body.extend_from_slice(&[0; 32]);
+        // No session id, one cipher suite, no compression
+        body.extend_from_slice(&[0, 0, 2, 0x13, 0x01, 1, 0]);
+        let mut extensions = vec![0x00, 0x0b, 0, 2, 1, 0];
+        if let Some(name) = name {
+            let name = name.as_bytes();
+            let mut names = vec![0];
+            names.extend_from_slice(&(name.len() as u16).to_be_bytes());
+            names.extend_from_slice(name);
+            let mut data = (names.len() as u16).to_be_bytes().to_vec();
+            data.extend(names);
+            extensions.extend_from_slice(&TLS_SERVER_NAME.to_be_bytes());
+            extensions.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            extensions.extend(data);
+        }
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend(extensions);
+        let mut hello = vec![TLS_CLIENT_HELLO];
+        hello.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        hello.extend(body);
+        hello
+    }
+
+    /// `hello` in handshake records of `size` bytes at most
+    fn records(hello: &[u8], size: usize) -> Vec<u8> {
+        let mut records = vec![];
+        for chunk in hello.chunks(size) {
+            records.extend_from_slice(&[TLS_HANDSHAKE, 3, 1]);
+            records.extend_from_slice(&(chunk.len() as u16).to_be_bytes());
+            records.extend_from_slice(chunk);
+        }
+        records
+    }
+
+    fn read(data: Vec<u8>) -> io::Result<(Vec<u8>, Vec<u8>)> {
+        task::block_on(read_hello(&mut Cursor::new(data)))
+    }
+
+    #[test]
+    fn server_names() {
+        let hello = client_hello(Some("a.test"));
+        assert_eq!(server_name(&hello).as_deref(), Some("a.test"));
+        assert_eq!(server_name(&client_hello(None)), None);
+        let mut other = hello;
+        other[0] = 2;
+        assert_eq!(server_name(&other), None);
+    }
+
+    #[test]
+    fn truncated_hello() {
+        let hello = client_hello(Some("a.test"));
+        for len in 0..hello.len() {
+            assert_eq!(server_name(&hello[..len]), None);
+        }
+    }
+
+    #[test]
+    fn fragmented_hello() {
+        let hello = client_hello(Some("a.test"));
+        let mut data = records(&hello, 16);
+        let len = data.len();
+        data.extend_from_slice(b"rest");
+        let (records, read) = read(data).unwrap();
+        assert_eq!(records.len(), len);
+        assert_eq!(read, hello);
+        assert_eq!(server_name(&read).as_deref(), Some("a.test"));
+    }
+
+    #[test]
+    fn cut_short() {
+        let data = records(&client_hello(Some("a.test")), 16);
+        let e = read(data[..data.len() - 3].to_vec()).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+        let e = read(b"GET / HTTP/1.1\r\n\r\n".to_vec()).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn hosts() {
+        let host = |head: &str| http_host(head.as_bytes());
+        let head = "GET / HTTP/1.1\r\nHOST: a.test:8080\r\n\r\n";
+        assert_eq!(host(head).as_deref(), Some("a.test"));
+        let head = "GET / HTTP/1.1\r\nHost: [::1]:80\r\n\r\n";
+        assert_eq!(host(head).as_deref(), Some("::1"));
+        let head = "GET / HTTP/1.1\r\n\r\nHost: a.test\r\n";
+        assert_eq!(host(head), None);
+    }
+}
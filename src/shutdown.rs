@@ -0,0 +1,80 @@
+use futures::channel::oneshot;
+use futures::future::Shared;
+use futures::FutureExt;
+use futures_timer::Delay;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+/// Seconds given to in-flight sessions once shut down, if not configured
+pub(crate) const DRAIN_TMOUT: u64 = 30;
+
+/// How often draining checks for the sessions left
+const DRAIN_POLL: Duration = Duration::from_millis(100);
+
+/// Stops a running server or client. New sessions are refused, in-flight
+/// ones are given some time to finish.
+#[derive(Clone)]
+pub struct Shutdown {
+    sender: Arc<Mutex<Option<oneshot::Sender<()>>>>,
+    signal: Shared<oneshot::Receiver<()>>,
+    done: Arc<AtomicBool>,
+    sessions: Arc<AtomicUsize>,
+}
+
+/// An in-flight session, counted until dropped
+pub(crate) struct SessionGuard(Arc<AtomicUsize>);
+
+impl Shutdown {
+    pub(crate) fn new() -> Shutdown {
+        let (sender, signal) = oneshot::channel();
+        Shutdown {
+            sender: Arc::new(Mutex::new(Some(sender))),
+            signal: signal.shared(),
+            done: Arc::new(AtomicBool::new(false)),
+            sessions: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn shutdown(&self) {
+        self.done.store(true, Ordering::Relaxed);
+        self.sender.lock().unwrap().take();
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.done.load(Ordering::Relaxed)
+    }
+
+    /// Resolve once shut down
+    pub(crate) async fn wait(&self) {
+        let _ = self.signal.clone().await;
+    }
+
+    pub(crate) fn enter(&self) -> SessionGuard {
+        self.sessions.fetch_add(1, Ordering::Relaxed);
+        SessionGuard(self.sessions.clone())
+    }
+
+    /// Wait for the in-flight sessions, at most `deadline`. Return the number
+    /// of sessions left.
+    pub(crate) async fn drain(&self, deadline: Duration) -> usize {
+        let start = Instant::now();
+        loop {
+            let left = self.sessions.load(Ordering::Relaxed);
+            if left == 0 || start.elapsed() >= deadline {
+                return left;
+            }
+            Delay::new(DRAIN_POLL).await;
+        }
+    }
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,151 @@
+use crate::heartbeat::now_ms;
+use crate::metrics::Counter;
+use async_std::io;
+use futures::future;
+use futures::io::AsyncRead;
+use futures::io::AsyncWrite;
+use futures::AsyncReadExt;
+use futures::FutureExt;
+use futures_timer::Delay;
+use std::pin::Pin;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::task::Context;
+use std::task::Poll;
+use std::time::Duration;
+
+/// Byte stream between peers, either plain TCP or TLS
+pub trait Stream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T> Stream for T where T: AsyncRead + AsyncWrite + Send + Unpin {}
+
+pub type BoxStream = Box<dyn Stream>;
+
+/// Copy data between `a` and `b`, return once either direction ends, or
+/// nothing went either way for `idle`.
+pub async fn pipe(a: BoxStream, b: BoxStream, idle: Option<Duration>) -> io::Result<()> {
+    let last = Arc::new(AtomicU64::new(now_ms()));
+    let a = Touched {
+        inner: a,
+        last: last.clone(),
+    };
+    let (ar, aw) = &mut a.split();
+    let (br, bw) = &mut b.split();
+    futures::select! {
+        r = io::copy(ar, bw).fuse() => r?,
+        r = io::copy(br, aw).fuse() => r?,
+        _ = expire(&last, idle).fuse() => Err(io::Error::from(io::ErrorKind::TimedOut))?,
+    };
+    Ok(())
+}
+
+/// Return once `last` is older than `idle`, never if None
+async fn expire(last: &AtomicU64, idle: Option<Duration>) {
+    let idle = match idle {
+        Some(idle) => idle,
+        None => return future::pending().await,
+    };
+    loop {
+        let since = Duration::from_millis(now_ms().saturating_sub(last.load(Ordering::Relaxed)));
+        if since >= idle {
+            return;
+        }
+        Delay::new(idle - since).await;
+    }
+}
+
+/// Keeps the time of the last read or write of a stream
+struct Touched<S> {
+    inner: S,
+    last: Arc<AtomicU64>,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Touched<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let r = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(_)) = r {
+            self.last.store(now_ms(), Ordering::Relaxed);
+        }
+        r
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Touched<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let r = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(_)) = r {
+            self.last.store(now_ms(), Ordering::Relaxed);
+        }
+        r
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+/// Counts the bytes read from and written to a stream
+pub struct Counted<S> {
+    inner: S,
+    read: Counter,
+    written: Counter,
+}
+
+impl<S> Counted<S> {
+    pub fn new(inner: S, read: Counter, written: Counter) -> Counted<S> {
+        Counted {
+            inner,
+            read,
+            written,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let r = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(len)) = r {
+            self.read.add(len as u64);
+        }
+        r
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let r = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(len)) = r {
+            self.written.add(len as u64);
+        }
+        r
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
@@ -0,0 +1,182 @@
+use async_std::net::TcpStream;
+use log::warn;
+use serde::Deserialize;
+use socket2::SockRef;
+use socket2::TcpKeepalive;
+use std::time::Duration;
+use thiserror::Error;
+
+/// Timeouts and keepalive intervals of a server or client
+#[derive(Clone, Copy, Debug)]
+pub struct Timeouts {
+    /// Between pings on the control connection
+    pub ping: Duration,
+    /// Pings left unanswered before the control connection is dropped
+    pub missed_pings: u32,
+    /// Longest wait for the next message of the peer, above `ping`
+    pub read: Duration,
+    /// Longest wait for the client to connect, to the server or back with
+    /// the stream of a visitor
+    pub connect: Duration,
+    /// Before the client connects again to the server, doubled after each
+    /// failure up to `reconnect_max`
+    pub reconnect: Duration,
+    pub reconnect_max: Duration,
+    /// TCP keepalive of visitor and local service connections, if any
+    pub keepalive: Option<Duration>,
+    /// Close TCP sessions idle both ways for so long, if any
+    pub idle: Option<Duration>,
+    /// Close UDP sessions idle both ways for so long
+    pub udp_idle: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Timeouts {
+        Timeouts {
+            ping: Duration::from_secs(5),
+            missed_pings: 3,
+            read: Duration::from_secs(10),
+            connect: Duration::from_secs(10),
+            reconnect: Duration::from_secs(3),
+            reconnect_max: Duration::from_secs(60),
+            keepalive: None,
+            idle: None,
+            udp_idle: Duration::from_secs(60),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum BadTimeouts {
+    #[error("ping must be above zero")]
+    Ping,
+    #[error("missed_pings must be above zero")]
+    MissedPings,
+    #[error("read timeout must be above ping")]
+    Read,
+    #[error("reconnect must be above zero")]
+    Reconnect,
+    #[error("reconnect_max must not be below reconnect")]
+    ReconnectMax,
+}
+
+impl Timeouts {
+    /// Check the peer gets pinged, and is waited for longer than a ping, and
+    /// that reconnects back off
+    pub fn validate(&self) -> Result<(), BadTimeouts> {
+        if self.ping == Duration::from_secs(0) {
+            Err(BadTimeouts::Ping)
+        } else if self.missed_pings == 0 {
+            Err(BadTimeouts::MissedPings)
+        } else if self.read <= self.ping {
+            Err(BadTimeouts::Read)
+        } else if self.reconnect == Duration::from_secs(0) {
+            Err(BadTimeouts::Reconnect)
+        } else if self.reconnect_max < self.reconnect {
+            Err(BadTimeouts::ReconnectMax)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Turn on TCP keepalive of `tcp`, if configured
+    pub(crate) fn keepalive(&self, tcp: &TcpStream) {
+        let time = match self.keepalive {
+            Some(time) => time,
+            None => return,
+        };
+        let keepalive = TcpKeepalive::new().with_time(time);
+        if let Err(e) = SockRef::from(tcp).set_tcp_keepalive(&keepalive) {
+            warn!(target: "shadow-peer", "Set keepalive: {}", e);
+        }
+    }
+}
+
+/// `Timeouts` as configured, in seconds, the default of those not given
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct TimeoutSecs {
+    pub ping: Option<u64>,
+    pub missed_pings: Option<u32>,
+    pub read_timeout: Option<u64>,
+    pub connect_timeout: Option<u64>,
+    pub reconnect: Option<u64>,
+    pub reconnect_max: Option<u64>,
+    pub keepalive: Option<u64>,
+    pub idle_timeout: Option<u64>,
+    pub udp_idle_timeout: Option<u64>,
+}
+
+impl TimeoutSecs {
+    pub fn timeouts(&self) -> Result<Timeouts, BadTimeouts> {
+        let default = Timeouts::default();
+        let secs = |secs: Option<u64>, default| secs.map(Duration::from_secs).unwrap_or(default);
+        let timeouts = Timeouts {
+            ping: secs(self.ping, default.ping),
+            missed_pings: self.missed_pings.unwrap_or(default.missed_pings),
+            read: secs(self.read_timeout, default.read),
+            connect: secs(self.connect_timeout, default.connect),
+            reconnect: secs(self.reconnect, default.reconnect),
+            reconnect_max: secs(self.reconnect_max, default.reconnect_max),
+            keepalive: self.keepalive.map(Duration::from_secs),
+            idle: self.idle_timeout.map(Duration::from_secs),
+            udp_idle: secs(self.udp_idle_timeout, default.udp_idle),
+        };
+        timeouts.validate()?;
+        Ok(timeouts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs() -> TimeoutSecs {
+        TimeoutSecs::default()
+    }
+
+    #[test]
+    fn defaults() {
+        let timeouts = secs().timeouts().unwrap();
+        assert_eq!(timeouts.ping, Timeouts::default().ping);
+        assert_eq!(timeouts.keepalive, None);
+    }
+
+    #[test]
+    fn pings() {
+        let ping = TimeoutSecs {
+            ping: Some(0),
+            ..secs()
+        };
+        assert!(matches!(ping.timeouts(), Err(BadTimeouts::Ping)));
+        let missed = TimeoutSecs {
+            missed_pings: Some(0),
+            ..secs()
+        };
+        assert!(matches!(missed.timeouts(), Err(BadTimeouts::MissedPings)));
+        let mut read = TimeoutSecs {
+            ping: Some(10),
+            read_timeout: Some(10),
+            ..secs()
+        };
+        assert!(matches!(read.timeouts(), Err(BadTimeouts::Read)));
+        read.read_timeout = Some(11);
+        assert!(read.timeouts().is_ok());
+    }
+
+    #[test]
+    fn reconnects() {
+        let zero = TimeoutSecs {
+            reconnect: Some(0),
+            ..secs()
+        };
+        assert!(matches!(zero.timeouts(), Err(BadTimeouts::Reconnect)));
+        let mut max = TimeoutSecs {
+            reconnect: Some(10),
+            reconnect_max: Some(5),
+            ..secs()
+        };
+        assert!(matches!(max.timeouts(), Err(BadTimeouts::ReconnectMax)));
+        max.reconnect_max = Some(10);
+        assert!(max.timeouts().is_ok());
+    }
+}
@@ -0,0 +1,47 @@
+use crate::error::Error;
+use crate::error::Result;
+pub use async_tls::TlsAcceptor;
+pub use async_tls::TlsConnector;
+use rustls::internal::pemfile;
+use rustls::ClientConfig;
+use rustls::NoClientAuth;
+use rustls::ServerConfig;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+/// Build a TLS acceptor from PEM encoded certificate chain and private key,
+/// the key could be either PKCS#8 or RSA.
+pub fn acceptor(cert: &Path, key: &Path) -> Result<TlsAcceptor> {
+    let cert = pemfile::certs(&mut BufReader::new(File::open(cert)?))
+        .map_err(|_| Error::Tls(format!("invalid certificate {:?}", cert)))?;
+    let key = load_key(key)?;
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config
+        .set_single_cert(cert, key)
+        .map_err(|e| Error::Tls(e.to_string()))?;
+    Ok(TlsAcceptor::from(config))
+}
+
+/// Build a TLS connector which only trusts the CA certificates in `ca`.
+pub fn connector(ca: &Path) -> Result<TlsConnector> {
+    let mut config = ClientConfig::new();
+    match config
+        .root_store
+        .add_pem_file(&mut BufReader::new(File::open(ca)?))
+    {
+        Ok((valid, _)) if valid > 0 => Ok(TlsConnector::from(config)),
+        _ => Err(Error::Tls(format!("no valid CA certificate in {:?}", ca))),
+    }
+}
+
+fn load_key(path: &Path) -> Result<rustls::PrivateKey> {
+    let pkcs8 = pemfile::pkcs8_private_keys(&mut BufReader::new(File::open(path)?));
+    let rsa = pemfile::rsa_private_keys(&mut BufReader::new(File::open(path)?));
+    pkcs8
+        .into_iter()
+        .chain(rsa)
+        .flatten()
+        .next()
+        .ok_or_else(|| Error::Tls(format!("no private key in {:?}", path)))
+}
@@ -1,8 +1,91 @@
-use std::time::SystemTime;
+use futures_timer::Delay;
+use rand::Rng;
+use std::time::Duration;
 
-pub fn current_time16() -> u16 {
-    match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
-        Ok(dur) => dur.as_secs() as u16,
-        Err(_) => 0,
+const BACKOFF_MIN: Duration = Duration::from_millis(5);
+const BACKOFF_MAX: Duration = Duration::from_secs(1);
+/// Least first delay, so that a range from zero still doubles
+const BACKOFF_FLOOR: Duration = Duration::from_millis(1);
+
+/// Delay after repeated failures, doubled each time up to a limit
+pub struct Backoff {
+    min: Duration,
+    max: Duration,
+    delay: Duration,
+}
+
+impl Backoff {
+    pub fn new() -> Backoff {
+        Backoff::range(BACKOFF_MIN, BACKOFF_MAX)
+    }
+
+    /// Starting at `min`, a millisecond at least, up to `max`
+    pub fn range(min: Duration, max: Duration) -> Backoff {
+        Backoff {
+            min: min.max(BACKOFF_FLOOR),
+            max,
+            delay: Duration::from_secs(0),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.delay = Duration::from_secs(0);
+    }
+
+    pub async fn wait(&mut self) {
+        Delay::new(self.next()).await;
+    }
+
+    /// The next delay, randomly cut by up to half so that peers failing
+    /// together do not retry together.
+    pub fn jittered(&mut self) -> Duration {
+        let delay = self.next();
+        delay / 2 + delay.mul_f64(rand::thread_rng().gen_range(0.0, 0.5))
+    }
+
+    fn next(&mut self) -> Duration {
+        self.delay = (self.delay * 2).max(self.min).min(self.max);
+        self.delay
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn doubles_up_to_max() {
+        let mut backoff = Backoff::range(ms(100), ms(500));
+        let delays: Vec<_> = (0..5).map(|_| backoff.next()).collect();
+        assert_eq!(delays, vec![ms(100), ms(200), ms(400), ms(500), ms(500)]);
+    }
+
+    #[test]
+    fn reset_starts_over() {
+        let mut backoff = Backoff::range(ms(100), ms(500));
+        backoff.next();
+        backoff.next();
+        backoff.reset();
+        assert_eq!(backoff.next(), ms(100));
+    }
+
+    #[test]
+    fn jittered_by_half_at_most() {
+        let mut backoff = Backoff::range(ms(1000), ms(1000));
+        for _ in 0..100 {
+            let delay = backoff.jittered();
+            assert!(delay >= ms(500) && delay < ms(1000));
+        }
+    }
+
+    #[test]
+    fn zero_min_grows() {
+        let mut backoff = Backoff::range(ms(0), ms(4));
+        let delays: Vec<_> = (0..4).map(|_| backoff.next()).collect();
+        assert_eq!(delays, vec![ms(1), ms(2), ms(4), ms(4)]);
     }
 }